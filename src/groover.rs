@@ -1,40 +1,140 @@
-use songbird::{Call, ConnectionInfo, Driver};
+use async_trait::async_trait;
+use songbird::{Call, ConnectionInfo, CoreEvent, Driver, Event, EventContext, EventHandler};
 use songbird::id::{GuildId, UserId};
 use songbird::input::Input;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Songbird driver events we act on. songbird 0.1 has no disconnect event, a driver that
+/// gives up reconnecting reports `ReconnectFailed` instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DriverEvent {
+    Connected,
+    Reconnected,
+    ConnectFailed,
+    ReconnectFailed,
+}
+
+struct DriverEventForwarder {
+    event: DriverEvent,
+    sender: UnboundedSender<DriverEvent>,
+}
+
+#[async_trait]
+impl EventHandler for DriverEventForwarder {
+    async fn act(&self, _: &EventContext<'_>) -> Option<Event> {
+        let _ = self.sender.send(self.event);
+        None
+    }
+}
 
-#[derive(Clone)]
 pub struct Groover {
     call: Call,
     is_connected: bool,
     pub is_source_set: bool,
+    bitrate: songbird::Bitrate,
+    /// Where to rejoin after losing the connection, cleared when leaving on purpose
+    last_info: Option<ConnectionInfo>,
+    /// Bumped on every join and leave, so a delayed rejoin can tell it was overtaken
+    generation: u64,
 }
 
 impl Groover {
     pub fn new(guild_id: String, user_id: String) -> Groover {
+        Groover::with_ids(GuildId::from(guild_id.clone().parse::<u64>().unwrap()), UserId::from(user_id.clone().parse::<u64>().unwrap()))
+    }
+
+    pub fn with_ids(guild_id: GuildId, user_id: UserId) -> Groover {
         Groover {
-            call : Call::standalone(GuildId::from(guild_id.clone().parse::<u64>().unwrap()), UserId::from(user_id.clone().parse::<u64>().unwrap())),
+            call: Call::standalone(guild_id, user_id),
             is_connected: false,
             is_source_set: false,
+            bitrate: songbird::Bitrate::Auto,
+            last_info: None,
+            generation: 0,
         }
     }
 
+    /// Starts forwarding the driver's connection events, only for calls something acts on
+    /// them for.
+    pub fn watch_events(&mut self) -> UnboundedReceiver<DriverEvent> {
+        let (sender, receiver) = unbounded_channel();
+
+        let events = [
+            (CoreEvent::DriverConnect, DriverEvent::Connected),
+            (CoreEvent::DriverReconnect, DriverEvent::Reconnected),
+            (CoreEvent::DriverConnectFailed, DriverEvent::ConnectFailed),
+            (CoreEvent::DriverReconnectFailed, DriverEvent::ReconnectFailed),
+        ];
+        for (core_event, event) in events.iter() {
+            self.call.add_global_event(
+                Event::Core(*core_event),
+                DriverEventForwarder { event: *event, sender: sender.clone() },
+            );
+        }
+
+        receiver
+    }
+
     pub async fn connect(&mut self, info: ConnectionInfo) {
         if self.is_connected {
             self.disconnect().await;
         }
+        self.last_info = Some(info.clone());
+        self.generation += 1;
         self.call.connect(info).await;
         self.is_connected = true;
     }
 
+    /// Connects again with the last connection info, the outcome arrives as a driver event.
+    pub async fn rejoin(&mut self) -> bool {
+        let info = match self.last_info.clone() {
+            Some(info) => info,
+            None => return false,
+        };
+
+        self.call.leave().await;
+        self.call.connect(info).await;
+        self.is_connected = true;
+        true
+    }
+
+    /// Whether the connection was lost rather than left.
+    pub fn should_rejoin(&self) -> bool {
+        self.last_info.is_some()
+    }
+
+    /// Changes whenever the call is joined or left on purpose.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     pub async fn disconnect(&mut self) {
+        self.last_info = None;
+        self.generation += 1;
         if self.is_connected {
             self.call.leave().await;
+            self.is_connected = false;
         }
+
+        // Drops the input, which unsubscribes it from the pipeline until the next join
+        self.call.stop();
+        self.is_source_set = false;
     }
 
+    pub fn is_connected(&self) -> bool {
+        self.is_connected
+    }
+
+    /// Replaces whatever the call was playing.
     pub fn set_source(&mut self, source: Input) {
+        self.call.stop();
         self.call.play_source(source);
-        self.call.set_bitrate(songbird::Bitrate::Auto);
+        self.call.set_bitrate(self.bitrate);
         self.is_source_set = true;
     }
+
+    pub fn set_bitrate(&mut self, bitrate: songbird::Bitrate) {
+        self.bitrate = bitrate;
+        self.call.set_bitrate(bitrate);
+    }
 }
\ No newline at end of file
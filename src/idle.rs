@@ -0,0 +1,64 @@
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+/// Runs something once playback has been idle for `IDLE_TIMEOUT_SECS`, unless it is
+/// cancelled or restarted first. A timeout of 0 disables it.
+#[derive(Clone)]
+pub struct IdleTimer {
+    timeout: Option<Duration>,
+    generation: Arc<AtomicU64>,
+    armed: Arc<AtomicBool>,
+}
+
+impl IdleTimer {
+    pub fn from_env() -> IdleTimer {
+        let secs = match env::var("IDLE_TIMEOUT_SECS") {
+            Ok(secs) => secs.parse().expect("Invalid IDLE_TIMEOUT_SECS"),
+            Err(_) => 600,
+        };
+
+        IdleTimer {
+            timeout: Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()),
+            generation: Arc::new(AtomicU64::new(0)),
+            armed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// (Re)starts the timer, `on_idle` runs when it expires.
+    pub fn start<F: Future<Output = ()> + Send + 'static>(&self, on_idle: F) {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.armed.store(true, Ordering::SeqCst);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            sleep(timeout).await;
+
+            if this.generation.load(Ordering::SeqCst) == generation && this.armed.swap(false, Ordering::SeqCst) {
+                on_idle.await;
+            }
+        });
+    }
+
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.armed.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,290 @@
+use std::{env, fs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use librespot::core::authentication::Credentials;
+use librespot::protocol::authentication::AuthenticationType;
+use serde::{Deserialize, Serialize};
+use spotify_oauth::{SpotifyAuth, SpotifyCallback, SpotifyScope};
+use tokio::net::TcpListener;
+
+use crate::lib::http;
+use crate::operator::Operator;
+
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const TOKEN_FILE: &str = "token.json";
+
+// Refresh this long before the access token expires
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+// Connections are served one at a time, an idle one must not hold up the login
+const CALLBACK_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct StoredToken {
+    access_token: String,
+    refresh_token: Option<String>,
+    /// Unix seconds, `None` for tokens passed through `TOKEN` that we know nothing about
+    expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RefreshResponse {
+    access_token: String,
+    expires_in: u64,
+    refresh_token: Option<String>,
+}
+
+/// The Spotify access token librespot authenticates with, kept fresh with the refresh
+/// token from the OAuth flow and persisted next to the librespot cache.
+#[derive(Clone)]
+pub struct TokenStore {
+    token: Arc<Mutex<StoredToken>>,
+    path: Option<PathBuf>,
+    client_id: String,
+    client_secret: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn scopes() -> Vec<SpotifyScope> {
+    vec![SpotifyScope::Streaming, SpotifyScope::UserReadPlaybackState, SpotifyScope::UserModifyPlaybackState, SpotifyScope::UserReadCurrentlyPlaying]
+}
+
+/// How the OAuth authorization code gets back to Groover.
+pub enum OAuthMode {
+    /// Print the authorize URL and read the callback URL from stdin
+    Stdin,
+    /// Serve the redirect URI ourselves, `listen_addr` defaults to the port in the URI
+    Callback { listen_addr: Option<String> },
+    /// Hand the authorize URL to the operator and wait for it to send back the code
+    Operator(Operator),
+}
+
+impl TokenStore {
+    /// Uses `TOKEN` if set, then a token saved by an earlier run, and falls back to the
+    /// OAuth flow.
+    pub async fn login(cache_dir: Option<String>, mode: OAuthMode) -> TokenStore {
+        let path = cache_dir.map(|dir| PathBuf::from(dir).join(TOKEN_FILE));
+        let client_id = env::var("SPOTIFY_CLIENT_ID").unwrap_or_default();
+        let client_secret = env::var("SPOTIFY_CLIENT_SECRET").unwrap_or_default();
+
+        if let Ok(token) = env::var("TOKEN") {
+            return TokenStore {
+                token: Arc::new(Mutex::new(StoredToken {
+                    access_token: token,
+                    refresh_token: None,
+                    expires_at: None,
+                })),
+                path: None,
+                client_id,
+                client_secret,
+            };
+        }
+
+        let saved = path.as_ref()
+            .and_then(|p| fs::read(p).ok())
+            .and_then(|data| serde_json::from_slice::<StoredToken>(&data).ok());
+
+        if let Some(saved) = saved {
+            let store = TokenStore {
+                token: Arc::new(Mutex::new(saved)),
+                path: path.clone(),
+                client_id: client_id.clone(),
+                client_secret: client_secret.clone(),
+            };
+
+            if !store.needs_refresh() {
+                return store;
+            }
+            match store.refresh().await {
+                Ok(()) => return store,
+                Err(e) => println!("Could not refresh saved token, logging in again: {}", e),
+            }
+        }
+
+        let auth = SpotifyAuth::new_from_env("code".into(), scopes(), false);
+        let auth_url = auth.authorize_url().expect("auth url");
+
+        let callback = match mode {
+            OAuthMode::Stdin => {
+                println!("{}", auth_url);
+
+                let mut buffer = String::new();
+                std::io::stdin().read_line(&mut buffer);
+                buffer.trim().to_string()
+            }
+            OAuthMode::Callback { listen_addr } => {
+                println!("Log in at {}", auth_url);
+                wait_for_callback(&auth.redirect_uri, listen_addr).await
+            }
+            OAuthMode::Operator(operator) => {
+                let reply = operator.request_auth(auth_url).await;
+
+                // Operators may hand back either the whole callback URL or just the code
+                if reply.starts_with("http") {
+                    reply
+                } else {
+                    format!("{}?code={}", auth.redirect_uri, reply)
+                }
+            }
+        };
+
+        // Convert the given callback URL into a token.
+        let token = SpotifyCallback::from_str(&callback).unwrap()
+            .convert_into_token(auth.client_id.clone(), auth.client_secret.clone(), auth.redirect_uri).await.expect("get token");
+
+        let store = TokenStore {
+            token: Arc::new(Mutex::new(StoredToken {
+                access_token: token.access_token,
+                refresh_token: Some(token.refresh_token),
+                expires_at: Some(now() + token.expires_in as u64),
+            })),
+            path,
+            client_id: auth.client_id,
+            client_secret: auth.client_secret,
+        };
+        store.save();
+
+        store
+    }
+
+    pub fn access_token(&self) -> String {
+        self.token.lock().unwrap().access_token.clone()
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        Credentials {
+            username: "".into(),
+            auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+            auth_data: self.access_token().into_bytes(),
+        }
+    }
+
+    pub fn can_refresh(&self) -> bool {
+        self.token.lock().unwrap().refresh_token.is_some()
+    }
+
+    /// Time until the token should be refreshed, `None` if it never expires as far as we know.
+    pub fn refresh_in(&self) -> Option<Duration> {
+        let expires_at = self.token.lock().unwrap().expires_at?;
+        let refresh_at = expires_at.saturating_sub(REFRESH_MARGIN.as_secs());
+
+        Some(Duration::from_secs(refresh_at.saturating_sub(now())))
+    }
+
+    fn needs_refresh(&self) -> bool {
+        self.refresh_in().map_or(false, |d| d.as_secs() == 0)
+    }
+
+    pub async fn refresh(&self) -> Result<(), String> {
+        let refresh_token = self.token.lock().unwrap().refresh_token.clone()
+            .ok_or_else(|| "no refresh token".to_string())?;
+
+        let response = reqwest::Client::new()
+            .post(TOKEN_URL)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("grant_type", "refresh_token"), ("refresh_token", refresh_token.as_str())])
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("token endpoint returned {}", response.status()));
+        }
+
+        let body = response.text().await.map_err(|e| e.to_string())?;
+        let refreshed: RefreshResponse = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+
+        {
+            let mut token = self.token.lock().unwrap();
+            token.access_token = refreshed.access_token;
+            token.expires_at = Some(now() + refreshed.expires_in);
+            // Spotify only sometimes rotates the refresh token
+            if let Some(refresh_token) = refreshed.refresh_token {
+                token.refresh_token = Some(refresh_token);
+            }
+        }
+        self.save();
+
+        Ok(())
+    }
+
+    /// Deletes the saved token, the in-memory one keeps working until it expires.
+    pub fn forget(&self) -> std::io::Result<()> {
+        match self.path.as_ref().map(fs::remove_file) {
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let data = serde_json::to_vec(&*self.token.lock().unwrap()).unwrap();
+        if let Err(e) = fs::write(path, data) {
+            println!("Could not save token to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Serves the redirect URI until Spotify sends the browser back with a code, and returns
+/// the full callback URL.
+async fn wait_for_callback(redirect_uri: &str, listen_addr: Option<String>) -> String {
+    // http://host:port/path, the port and path are all we need
+    let without_scheme = redirect_uri.splitn(2, "://").nth(1).unwrap_or(redirect_uri);
+    let mut parts = without_scheme.splitn(2, '/');
+    let authority = parts.next().unwrap_or("");
+    let callback_path = format!("/{}", parts.next().unwrap_or(""));
+    let port = authority.rsplitn(2, ':').next().filter(|p| p.parse::<u16>().is_ok()).unwrap_or("80");
+
+    let addr = listen_addr.unwrap_or_else(|| format!("0.0.0.0:{}", port));
+    let listener = TcpListener::bind(&addr).await.expect("Could not bind OAuth callback listener");
+    println!("Waiting for the OAuth callback on {}", addr);
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(_) => continue,
+        };
+
+        let request = match tokio::time::timeout(CALLBACK_READ_TIMEOUT, http::read_request(&mut stream)).await {
+            Ok(Ok(request)) => request,
+            _ => continue,
+        };
+
+        // Spotify redirects with `error` instead of `code` when the login was denied
+        if let (true, Some(error)) = (request.path() == callback_path, request.query("error")) {
+            println!("Spotify login failed: {}, waiting for another attempt", error);
+            let _ = http::respond(
+                &mut stream,
+                "400 Bad Request",
+                "text/html",
+                b"<html><body>Spotify login failed, open the login link again to retry.</body></html>",
+            ).await;
+            continue;
+        }
+
+        if request.path() != callback_path || request.query("code").is_none() {
+            let _ = http::respond(&mut stream, "404 Not Found", "text/plain", b"not found").await;
+            continue;
+        }
+
+        let _ = http::respond(
+            &mut stream,
+            "200 OK",
+            "text/html",
+            b"<html><body>Groover is logged in, you can close this tab.</body></html>",
+        ).await;
+
+        let query = request.target.splitn(2, '?').nth(1).unwrap_or("");
+        return format!("{}?{}", redirect_uri.trim_end_matches('/'), query);
+    }
+}
@@ -0,0 +1,215 @@
+use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+
+use librespot::audio::AudioFile;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use librespot::metadata::{Album, FileFormat, Metadata, Playlist, Track};
+use librespot::playback::config::Bitrate;
+use serde::Serialize;
+
+use crate::lib::offline::OfflineIndex;
+
+// 4 GB
+const DEFAULT_SIZE_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Where librespot keeps its cache and how large the audio part may grow.
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    /// Credentials, volume and the OAuth token
+    pub credentials_dir: Option<String>,
+    pub audio_dir: Option<String>,
+    pub size_limit: u64,
+}
+
+impl CacheSettings {
+    /// `CACHE_DIR` sets both directories, `CREDENTIALS_CACHE_DIR` and `AUDIO_CACHE_DIR`
+    /// override them one by one. `CACHE_SIZE_LIMIT` is in bytes.
+    pub fn from_env() -> CacheSettings {
+        let cache_dir = env::var("CACHE_DIR").ok();
+
+        CacheSettings {
+            credentials_dir: env::var("CREDENTIALS_CACHE_DIR").ok().or_else(|| cache_dir.clone()),
+            audio_dir: env::var("AUDIO_CACHE_DIR").ok().or(cache_dir),
+            size_limit: match env::var("CACHE_SIZE_LIMIT") {
+                Ok(limit) => limit.parse().expect("Invalid CACHE_SIZE_LIMIT, expected bytes"),
+                Err(_) => DEFAULT_SIZE_LIMIT,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CacheUsage {
+    pub directory: Option<String>,
+    pub files: u64,
+    pub bytes: u64,
+    pub limit_bytes: u64,
+}
+
+/// What a `Prefetch` did, per track of the playlist or album.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PrefetchSummary {
+    pub tracks: u32,
+    pub fetched: u32,
+    pub already_cached: u32,
+    /// URIs of tracks that could not be fetched
+    pub failed: Vec<String>,
+}
+
+// librespot puts every audio file in a subdirectory named after the first two hex digits
+// of its ID, anything at the top level belongs to the credentials side of the cache
+fn audio_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(entry.path())? {
+            let file = file?;
+            if file.file_type()?.is_file() {
+                files.push(file.path());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+pub fn usage(settings: &CacheSettings) -> io::Result<CacheUsage> {
+    let mut usage = CacheUsage {
+        directory: settings.audio_dir.clone(),
+        files: 0,
+        bytes: 0,
+        limit_bytes: settings.size_limit,
+    };
+
+    if let Some(dir) = settings.audio_dir.as_ref() {
+        for file in audio_files(Path::new(dir))? {
+            usage.files += 1;
+            usage.bytes += fs::metadata(file)?.len();
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Deletes every cached audio file, credentials are left alone. Returns what was removed.
+pub fn purge(settings: &CacheSettings) -> io::Result<CacheUsage> {
+    let removed = usage(settings)?;
+
+    if let Some(dir) = settings.audio_dir.as_ref() {
+        for file in audio_files(Path::new(dir))? {
+            fs::remove_file(file)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+fn file_format(bitrate: Bitrate) -> FileFormat {
+    match bitrate {
+        Bitrate::Bitrate96 => FileFormat::OGG_VORBIS_96,
+        Bitrate::Bitrate160 => FileFormat::OGG_VORBIS_160,
+        Bitrate::Bitrate320 => FileFormat::OGG_VORBIS_320,
+    }
+}
+
+// Same rates librespot's player streams at
+fn bytes_per_second(bitrate: Bitrate) -> usize {
+    match bitrate {
+        Bitrate::Bitrate96 => 12 * 1024,
+        Bitrate::Bitrate160 => 20 * 1024,
+        Bitrate::Bitrate320 => 40 * 1024,
+    }
+}
+
+/// The tracks of a `spotify:playlist:` or `spotify:album:` URI.
+async fn tracks(session: &Session, uri: &str) -> Result<Vec<SpotifyId>, String> {
+    let id = uri.rsplit(':').next().unwrap_or(uri);
+    let id = SpotifyId::from_base62(id).map_err(|_| format!("invalid Spotify URI {}", uri))?;
+
+    if uri.contains(":playlist:") {
+        Playlist::get(session, id).await
+            .map(|p| p.tracks)
+            .map_err(|e| format!("could not load playlist: {:?}", e))
+    } else if uri.contains(":album:") {
+        Album::get(session, id).await
+            .map(|a| a.tracks)
+            .map_err(|e| format!("could not load album: {:?}", e))
+    } else {
+        Err(format!("{} is not a playlist or album", uri))
+    }
+}
+
+/// The file the player would stream for `track`.
+fn track_file(track: &Track, bitrate: Bitrate) -> Option<FileId> {
+    // Fall back to any format, like the player does for tracks without the wanted one
+    track.files.get(&file_format(bitrate))
+        .or_else(|| track.files.values().next())
+        .copied()
+}
+
+/// Remembers the file and audio key of a track, without the key a cached file is
+/// useless offline.
+pub async fn index_track(session: &Session, index: &OfflineIndex, id: SpotifyId, bitrate: Bitrate) -> Result<FileId, String> {
+    let track = Track::get(session, id).await.map_err(|e| format!("{:?}", e))?;
+    let file_id = track_file(&track, bitrate).ok_or_else(|| "no audio files".to_string())?;
+
+    if !index.contains(&id.to_uri()) {
+        index.learn(session, id, track.name, file_id).await?;
+    }
+
+    Ok(file_id)
+}
+
+/// Downloads one track in the format the player streams at, librespot writes it into
+/// the cache once the download completes. Returns false if it was cached already.
+async fn fetch_track(session: &Session, index: &OfflineIndex, id: SpotifyId, bitrate: Bitrate) -> Result<bool, String> {
+    let file_id = index_track(session, index, id, bitrate).await?;
+
+    let file = AudioFile::open(session, file_id, bytes_per_second(bitrate), true)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+
+    let mut file = match file {
+        AudioFile::Cached(_) => return Ok(false),
+        streaming => streaming,
+    };
+    file.get_stream_loader_controller().set_stream_mode();
+
+    // Reads block until the data is there, reading to the end pulls in the whole file
+    tokio::task::spawn_blocking(move || io::copy(&mut file, &mut io::sink()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    Ok(true)
+}
+
+/// Fetches every track of a playlist or album into the cache, one at a time.
+/// The audio keys and the track list are kept in `index` for offline playback.
+pub async fn prefetch(session: &Session, index: &OfflineIndex, uri: &str, bitrate: Bitrate) -> Result<PrefetchSummary, String> {
+    let tracks = tracks(session, uri).await?;
+    let mut summary = PrefetchSummary::default();
+
+    index.record_collection(uri.to_string(), &tracks);
+
+    for id in tracks {
+        summary.tracks += 1;
+
+        match fetch_track(session, index, id, bitrate).await {
+            Ok(true) => summary.fetched += 1,
+            Ok(false) => summary.already_cached += 1,
+            Err(e) => {
+                println!("Could not prefetch {}: {}", id.to_uri(), e);
+                summary.failed.push(id.to_uri());
+            }
+        }
+    }
+
+    Ok(summary)
+}
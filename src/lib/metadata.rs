@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::{Album, Artist, Metadata, Track};
+use serde::Serialize;
+
+// Entries kept per cache, the oldest lookup goes first
+const CACHE_CAPACITY: usize = 512;
+
+/// What is playing, as published on `<guild id>.events`.
+#[derive(Serialize, Debug, Clone)]
+pub struct TrackInfo {
+    pub uri: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub duration_ms: u32,
+    /// File IDs of the album covers, `https://i.scdn.co/image/<id>` serves them
+    pub cover_ids: Vec<String>,
+}
+
+impl TrackInfo {
+    /// "Artist, Artist - Title"
+    pub fn display_title(&self) -> String {
+        format!("{} - {}", self.artists.join(", "), self.title)
+    }
+}
+
+struct Bounded<K, V> {
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Bounded<K, V> {
+    fn new() -> Bounded<K, V> {
+        Bounded {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.order.len() > CACHE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Resolves tracks through Mercury, remembering tracks and artists we looked up before.
+#[derive(Clone)]
+pub struct MetadataCache {
+    tracks: Arc<Mutex<Bounded<SpotifyId, TrackInfo>>>,
+    artists: Arc<Mutex<Bounded<SpotifyId, String>>>,
+}
+
+impl MetadataCache {
+    pub fn new() -> MetadataCache {
+        MetadataCache {
+            tracks: Arc::new(Mutex::new(Bounded::new())),
+            artists: Arc::new(Mutex::new(Bounded::new())),
+        }
+    }
+
+    pub async fn track(&self, session: &Session, id: SpotifyId) -> Result<TrackInfo, String> {
+        if let Some(info) = self.tracks.lock().unwrap().get(&id) {
+            return Ok(info);
+        }
+
+        let track = Track::get(session, id).await.map_err(|e| format!("could not load track: {:?}", e))?;
+        let album = Album::get(session, track.album).await.map_err(|e| format!("could not load album: {:?}", e))?;
+
+        let mut artists = Vec::new();
+        for artist in track.artists.iter() {
+            artists.push(self.artist(session, *artist).await?);
+        }
+
+        let info = TrackInfo {
+            uri: id.to_uri(),
+            title: track.name,
+            artists,
+            album: album.name,
+            duration_ms: track.duration.max(0) as u32,
+            cover_ids: album.covers.iter().map(|c| c.to_base16()).collect(),
+        };
+        self.tracks.lock().unwrap().insert(id, info.clone());
+
+        Ok(info)
+    }
+
+    async fn artist(&self, session: &Session, id: SpotifyId) -> Result<String, String> {
+        if let Some(name) = self.artists.lock().unwrap().get(&id) {
+            return Ok(name);
+        }
+
+        let artist = Artist::get(session, id).await.map_err(|e| format!("could not load artist: {:?}", e))?;
+        self.artists.lock().unwrap().insert(id, artist.name.clone());
+
+        Ok(artist.name)
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt::Write;
+
+use tokio::net::TcpListener;
+
+use crate::lib::http;
+use crate::lib::pipeline::{Pipeline, SubscriberMetrics};
+use crate::lib::sink::{EmittedSink, SinkMetricsSnapshot};
+
+/// Serves the sink and pipeline metrics in the Prometheus text format on `GET /metrics`.
+pub async fn serve(addr: String, sink: EmittedSink, pipeline: Pipeline) {
+    let listener = TcpListener::bind(&addr).await.expect("Could not bind METRICS_ADDR");
+    println!("Serving metrics on {}", addr);
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Could not accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let sink = sink.clone();
+        let pipeline = pipeline.clone();
+
+        tokio::spawn(async move {
+            let request = match http::read_request(&mut stream).await {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+
+            let _ = if request.path() == "/metrics" {
+                let body = render(&sink.metrics(), &pipeline.subscriber_metrics());
+                http::respond(&mut stream, "200 OK", "text/plain; version=0.0.4", body.as_bytes()).await
+            } else {
+                http::respond(&mut stream, "404 Not Found", "text/plain", b"not found").await
+            };
+        });
+    }
+}
+
+fn render(m: &SinkMetricsSnapshot, subscribers: &[SubscriberMetrics]) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP groover_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE groover_{} {}", name, kind);
+        let _ = writeln!(out, "groover_{} {}", name, value);
+    };
+
+    metric("sink_queued_chunks", "gauge", "Packets waiting to be read from the sink", m.queued_chunks as f64);
+    metric("sink_queued_bytes", "gauge", "Bytes waiting to be read from the sink", m.queued_bytes as f64);
+    metric("sink_capacity_chunks", "gauge", "Packets the sink can hold", m.capacity_chunks as f64);
+    metric("sink_underruns_total", "counter", "Reads padded with silence while playing", m.underruns as f64);
+    metric("sink_overruns_total", "counter", "Packets discarded without a consumer", m.overruns as f64);
+    metric("sink_packets_total", "counter", "Packets written by librespot", m.packets as f64);
+    metric("sink_resample_seconds_last", "gauge", "Resampling time of the last packet", m.resample_us_last / 1e6);
+    metric("sink_resample_seconds_avg", "gauge", "Average resampling time per packet", m.resample_us_avg / 1e6);
+    metric("sink_latency_seconds_last", "gauge", "Delay between a packet being written and songbird reading it", m.latency_ms_last / 1e3);
+    metric("sink_latency_seconds_avg", "gauge", "Average delay between a packet being written and songbird reading it", m.latency_ms_avg / 1e3);
+
+    let _ = writeln!(out, "# HELP groover_pipeline_dropped_frames_total Frames lost because a subscriber fell behind");
+    let _ = writeln!(out, "# TYPE groover_pipeline_dropped_frames_total counter");
+    for s in subscribers {
+        let _ = writeln!(out, "groover_pipeline_dropped_frames_total{{subscriber=\"{}\",kind=\"{}\"}} {}", s.id, s.kind, s.dropped_frames);
+    }
+
+    out
+}
@@ -0,0 +1,294 @@
+use std::{fs, io, thread};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use librespot::audio::{AudioDecoder, AudioDecrypt, VorbisDecoder};
+use librespot::core::audio_key::AudioKey;
+use librespot::core::cache::Cache;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use librespot::playback::audio_backend::Sink;
+use serde::{Deserialize, Serialize};
+
+use crate::lib::sink::EmittedSink;
+
+const INDEX_FILE: &str = "offline.json";
+
+// Spotify's Ogg files start with a header of their own before the Ogg data
+const SPOTIFY_HEADER_SIZE: u64 = 0xa7;
+
+/// Everything needed to play a cached file without the access point. librespot caches
+/// the encrypted audio but not the key, so we keep that ourselves.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexedTrack {
+    pub uri: String,
+    pub name: String,
+    file_id: String,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    /// Keyed by track URI
+    tracks: HashMap<String, IndexedTrack>,
+    /// Track URIs of prefetched playlists and albums
+    collections: HashMap<String, Vec<String>>,
+}
+
+/// A requested item that can't be played offline.
+#[derive(Serialize, Debug, Clone)]
+pub struct Unavailable {
+    pub uri: String,
+    pub reason: String,
+}
+
+/// Tracks and collections we could play from the cache, persisted next to the audio files.
+#[derive(Clone)]
+pub struct OfflineIndex {
+    index: Arc<Mutex<Index>>,
+    path: Option<PathBuf>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    if hex.len() != N * 2 {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+impl OfflineIndex {
+    pub fn load(audio_dir: Option<String>) -> OfflineIndex {
+        let path = audio_dir.map(|dir| PathBuf::from(dir).join(INDEX_FILE));
+
+        let index = path.as_ref()
+            .and_then(|p| fs::read(p).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        OfflineIndex {
+            index: Arc::new(Mutex::new(index)),
+            path,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.lock().unwrap().tracks.is_empty()
+    }
+
+    pub fn contains(&self, uri: &str) -> bool {
+        self.index.lock().unwrap().tracks.contains_key(uri)
+    }
+
+    pub fn record_track(&self, id: SpotifyId, name: String, file_id: FileId, key: AudioKey) {
+        let track = IndexedTrack {
+            uri: id.to_uri(),
+            name,
+            file_id: to_hex(&file_id.0),
+            key: to_hex(&key.0),
+        };
+
+        self.index.lock().unwrap().tracks.insert(track.uri.clone(), track);
+        self.save();
+    }
+
+    pub fn record_collection(&self, uri: String, tracks: &[SpotifyId]) {
+        let tracks = tracks.iter().map(|id| id.to_uri()).collect();
+
+        self.index.lock().unwrap().collections.insert(uri, tracks);
+        self.save();
+    }
+
+    /// Looks the key for the track up through the session and remembers it, for tracks
+    /// that are being played or prefetched.
+    pub async fn learn(&self, session: &Session, id: SpotifyId, name: String, file_id: FileId) -> Result<(), String> {
+        let key = session.audio_key().request(id, file_id)
+            .await
+            .map_err(|e| format!("could not get audio key: {:?}", e))?;
+
+        self.record_track(id, name, file_id, key);
+        Ok(())
+    }
+
+    /// Splits track, playlist and album URIs into what can be played from `cache` and
+    /// what can't.
+    pub fn resolve(&self, cache: Option<&Cache>, uris: &[String]) -> (Vec<IndexedTrack>, Vec<Unavailable>) {
+        let index = self.index.lock().unwrap();
+        let mut playable = Vec::new();
+        let mut unavailable = Vec::new();
+
+        for uri in uris {
+            let track_uris = match index.collections.get(uri) {
+                Some(tracks) => tracks.clone(),
+                None if uri.contains(":track:") => vec![uri.clone()],
+                None => {
+                    unavailable.push(Unavailable { uri: uri.clone(), reason: "not prefetched".into() });
+                    continue;
+                }
+            };
+
+            for track_uri in track_uris {
+                let track = match index.tracks.get(&track_uri) {
+                    Some(track) => track,
+                    None => {
+                        unavailable.push(Unavailable { uri: track_uri, reason: "audio key unknown".into() });
+                        continue;
+                    }
+                };
+
+                let cached = cache
+                    .zip(track.file_id())
+                    .map_or(false, |(cache, file_id)| cache.file(file_id).is_some());
+                if cached {
+                    playable.push(track.clone());
+                } else {
+                    unavailable.push(Unavailable { uri: track_uri, reason: "audio not cached".into() });
+                }
+            }
+        }
+
+        (playable, unavailable)
+    }
+
+    fn save(&self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let data = serde_json::to_vec(&*self.index.lock().unwrap()).unwrap();
+        if let Err(e) = fs::write(path, data) {
+            println!("Could not save offline index to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl IndexedTrack {
+    fn file_id(&self) -> Option<FileId> {
+        from_hex(&self.file_id).map(FileId)
+    }
+
+    fn key(&self) -> Option<AudioKey> {
+        from_hex(&self.key).map(AudioKey)
+    }
+}
+
+/// Skips Spotify's header so the decoder sees plain Ogg.
+struct Subfile<R: Read + Seek> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: Read + Seek> Subfile<R> {
+    fn new(mut inner: R, offset: u64) -> io::Result<Subfile<R>> {
+        inner.seek(SeekFrom::Start(offset))?;
+        Ok(Subfile { inner, offset })
+    }
+}
+
+impl<R: Read + Seek> Read for Subfile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read + Seek> Seek for Subfile<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(offset + self.offset),
+            other => other,
+        };
+
+        let position = self.inner.seek(pos)?;
+        Ok(position - self.offset)
+    }
+}
+
+/// Plays cached tracks straight into the sink while there is no session to run the
+/// librespot player on.
+pub struct OfflinePlayer {
+    running: Arc<AtomicBool>,
+}
+
+impl OfflinePlayer {
+    pub fn play(mut sink: EmittedSink, cache: Cache, tracks: Vec<IndexedTrack>) -> OfflinePlayer {
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+
+        thread::spawn(move || {
+            let _ = sink.start();
+
+            for track in tracks {
+                if !running_clone.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                println!("Playing {} from the cache", track.name);
+                if let Err(e) = play_track(&mut sink, &cache, &track, &running_clone) {
+                    println!("Could not play {} offline: {}", track.uri, e);
+                }
+            }
+
+            let _ = sink.stop();
+            running_clone.store(false, Ordering::Relaxed);
+        });
+
+        OfflinePlayer { running }
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+fn play_track(sink: &mut EmittedSink, cache: &Cache, track: &IndexedTrack, running: &AtomicBool) -> Result<(), String> {
+    let file_id = track.file_id().ok_or_else(|| "corrupt file ID".to_string())?;
+    let key = track.key().ok_or_else(|| "corrupt audio key".to_string())?;
+    let file = cache.file(file_id).ok_or_else(|| "audio not cached".to_string())?;
+
+    let decrypted = AudioDecrypt::new(key, file);
+    let ogg = Subfile::new(decrypted, SPOTIFY_HEADER_SIZE).map_err(|e| e.to_string())?;
+    let mut decoder = VorbisDecoder::new(ogg).map_err(|e| format!("{:?}", e))?;
+
+    // The sink blocks while its queue is full, which paces decoding to playback speed
+    while running.load(Ordering::Relaxed) {
+        match decoder.next_packet() {
+            Ok(Some(packet)) => sink.write(&packet).map_err(|e| e.to_string())?,
+            Ok(None) => break,
+            Err(e) => return Err(format!("{:?}", e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether we are running without a session, and what is playing from the cache meanwhile.
+#[derive(Default)]
+pub struct OfflineMode {
+    pub active: bool,
+    player: Option<OfflinePlayer>,
+}
+
+impl OfflineMode {
+    pub fn play(&mut self, sink: EmittedSink, cache: Cache, tracks: Vec<IndexedTrack>) {
+        self.stop();
+        sink.flush();
+        self.player = Some(OfflinePlayer::play(sink, cache, tracks));
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(player) = self.player.take() {
+            player.stop();
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::{io, thread};
+use std::io::Read;
+use std::sync::{
+    Arc,
+    mpsc::{Receiver, RecvTimeoutError, sync_channel, SyncSender, TryRecvError, TrySendError}, Mutex,
+};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use audiopus::coder::Encoder;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+use songbird::input;
+use songbird::input::Input;
+
+use crate::lib::sink::EmittedSink;
+
+/// 20ms of interleaved 48kHz stereo, the frame size Discord uses.
+pub const FRAME_SAMPLES: usize = 960 * 2;
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+// Frames queued per subscriber, a subscriber that falls further behind loses frames
+const SUBSCRIBER_CAPACITY: usize = 8;
+
+// Opus frame decoding to 20ms of silence
+const SILENCE_FRAME: [u8; 3] = [0xF8, 0xFF, 0xFE];
+
+/// Samples the decoder discards at the start of an Ogg Opus stream.
+pub const OPUS_PRE_SKIP: u16 = 312;
+
+/// RFC 7845 identification header for a 48kHz stereo stream, it goes on its own page.
+pub fn ogg_opus_head() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(2);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+/// RFC 7845 comment header, `comments` are `KEY=value` pairs.
+pub fn ogg_opus_tags(comments: &[String]) -> Vec<u8> {
+    let vendor = b"groover";
+
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        tags.extend_from_slice(comment.as_bytes());
+    }
+    tags
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Pcm,
+    Opus,
+}
+
+#[derive(Clone)]
+pub struct Frame {
+    /// Sink epoch the audio was read under, frames from before a flush are skipped
+    pub epoch: u64,
+    pub pcm: Arc<Vec<f32>>,
+    /// Only encoded while at least one subscriber asked for Opus
+    pub opus: Option<Arc<Vec<u8>>>,
+    /// When librespot wrote the audio the frame starts with, `None` for silence
+    pub written_at: Option<Instant>,
+}
+
+struct Subscriber {
+    id: u64,
+    kind: FrameKind,
+    sender: SyncSender<Frame>,
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SubscriberMetrics {
+    pub id: u64,
+    pub kind: String,
+    /// Frames lost because the subscriber's queue was full
+    pub dropped_frames: u64,
+}
+
+/// Reads the sink on a 20ms clock and hands every frame to all subscribers, encoding
+/// it to Opus once no matter how many outputs need it.
+#[derive(Clone)]
+pub struct Pipeline {
+    sink: EmittedSink,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    running: Arc<AtomicBool>,
+    /// Opus bitrate in bits per second, 0 lets the encoder pick
+    opus_bitrate: Arc<AtomicI32>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Pipeline {
+    pub fn start(sink: EmittedSink) -> Pipeline {
+        let pipeline = Pipeline {
+            sink,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            running: Arc::new(AtomicBool::new(true)),
+            opus_bitrate: Arc::new(AtomicI32::new(0)),
+            next_id: Arc::new(AtomicU64::new(0)),
+        };
+
+        let worker = pipeline.clone();
+        thread::spawn(move || worker.run());
+
+        pipeline
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Encodes at `bits_per_second` from the next frame on, `None` goes back to the
+    /// encoder's own choice.
+    pub fn set_opus_bitrate(&self, bits_per_second: Option<i32>) {
+        self.opus_bitrate.store(bits_per_second.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Every subscriber gets its own queue, a slow one only loses its own frames and
+    /// never holds up the others.
+    pub fn subscribe(&self, kind: FrameKind) -> FrameReader {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        });
+
+        FrameReader {
+            receiver,
+            kind,
+            sink: self.sink.clone(),
+            pending: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    pub fn subscriber_metrics(&self) -> Vec<SubscriberMetrics> {
+        self.subscribers.lock().unwrap().iter()
+            .map(|s| SubscriberMetrics {
+                id: s.id,
+                kind: format!("{:?}", s.kind).to_lowercase(),
+                dropped_frames: s.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn run(mut self) {
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+            .expect("Could not create Opus encoder");
+        let mut bytes = vec![0; FRAME_SAMPLES * 4];
+        let mut attached = false;
+        let mut bitrate = 0;
+        let mut next_tick = Instant::now();
+
+        while self.running.load(Ordering::Relaxed) {
+            next_tick += FRAME_DURATION;
+            let now = Instant::now();
+            if next_tick > now {
+                thread::sleep(next_tick - now);
+            } else if now - next_tick > FRAME_DURATION * 5 {
+                // Too far behind to catch up, start counting from now again
+                next_tick = now;
+            }
+
+            let has_subscribers = !self.subscribers.lock().unwrap().is_empty();
+            if has_subscribers != attached {
+                // Without subscribers nothing is listening, let the backpressure policy decide
+                self.sink.set_consumer_attached(has_subscribers);
+                attached = has_subscribers;
+            }
+            if !has_subscribers {
+                continue;
+            }
+
+            let wanted = self.opus_bitrate.load(Ordering::Relaxed);
+            if wanted != bitrate {
+                let setting = if wanted > 0 { Bitrate::BitsPerSecond(wanted) } else { Bitrate::Auto };
+                if let Err(e) = encoder.set_bitrate(setting) {
+                    println!("Could not set Opus bitrate to {}: {:?}", wanted, e);
+                }
+                bitrate = wanted;
+            }
+
+            let epoch = self.sink.epoch();
+            let (n, written_at) = self.sink.read_timed(&mut bytes).unwrap_or((0, None));
+            if n == 0 {
+                // Sink closed
+                break;
+            }
+
+            let mut pcm = vec![0.0; FRAME_SAMPLES];
+            LittleEndian::read_f32_into(&bytes, &mut pcm);
+
+            self.publish(&mut encoder, epoch, pcm, written_at);
+        }
+
+        self.subscribers.lock().unwrap().clear();
+    }
+
+    fn publish(&self, encoder: &mut Encoder, epoch: u64, pcm: Vec<f32>, written_at: Option<Instant>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        let opus = if subscribers.iter().any(|s| s.kind == FrameKind::Opus) {
+            let mut packet = vec![0; 4000];
+            match encoder.encode_float(&pcm, &mut packet) {
+                Ok(len) => {
+                    packet.truncate(len);
+                    Some(Arc::new(packet))
+                }
+                Err(e) => {
+                    println!("Could not encode Opus frame: {:?}", e);
+                    Some(Arc::new(SILENCE_FRAME.to_vec()))
+                }
+            }
+        } else {
+            None
+        };
+
+        let frame = Frame {
+            epoch,
+            pcm: Arc::new(pcm),
+            opus,
+            written_at,
+        };
+
+        subscribers.retain(|s| match s.sender.try_send(frame.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                s.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// One subscriber's view of the pipeline, readable by songbird.
+pub struct FrameReader {
+    receiver: Receiver<Frame>,
+    kind: FrameKind,
+    sink: EmittedSink,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl FrameReader {
+    /// Waits for the next frame newer than the last flush.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Frame, RecvTimeoutError> {
+        loop {
+            let frame = self.receiver.recv_timeout(timeout)?;
+
+            if frame.epoch >= self.sink.epoch() {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Builds a songbird input, Opus frames are passed through to Discord without re-encoding.
+    pub fn input(self) -> Input {
+        match self.kind {
+            FrameKind::Pcm => Input::new(
+                true,
+                input::reader::Reader::Extension(Box::new(self)),
+                input::codec::Codec::FloatPcm,
+                input::Container::Raw,
+                None,
+            ),
+            FrameKind::Opus => Input::new(
+                true,
+                input::reader::Reader::Extension(Box::new(self)),
+                input::codec::Codec::Opus(
+                    input::codec::OpusDecoderState::new().expect("Could not create Opus decoder"),
+                ),
+                input::Container::Dca { first_frame: 0 },
+                None,
+            ),
+        }
+    }
+
+    fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        let frame = loop {
+            match self.receiver.try_recv() {
+                Ok(frame) if frame.epoch < self.sink.epoch() => continue,
+                Ok(frame) => break Some(frame),
+                Err(TryRecvError::Empty) => break None,
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        };
+
+        if let Some(written_at) = frame.as_ref().and_then(|f| f.written_at) {
+            self.sink.record_latency(written_at);
+        }
+
+        Some(match self.kind {
+            FrameKind::Pcm => {
+                let mut data = vec![0; FRAME_SAMPLES * 4];
+                if let Some(frame) = frame {
+                    LittleEndian::write_f32_into(&frame.pcm, &mut data);
+                }
+                data
+            }
+            FrameKind::Opus => {
+                // DCA framing, every packet is prefixed with its length
+                let packet = frame
+                    .and_then(|f| f.opus)
+                    .map(|p| p.to_vec())
+                    .unwrap_or_else(|| SILENCE_FRAME.to_vec());
+                let mut data = (packet.len() as i16).to_le_bytes().to_vec();
+                data.extend_from_slice(&packet);
+                data
+            }
+        })
+    }
+}
+
+impl io::Read for FrameReader {
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, io::Error> {
+        let mut written = 0;
+
+        while written < buff.len() {
+            if self.offset == self.pending.len() {
+                // Underruns are padded with silence rather than blocking the mixer
+                self.pending = match self.next_chunk() {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                self.offset = 0;
+            }
+
+            let n = (buff.len() - written).min(self.pending.len() - self.offset);
+            buff[written..written + n].copy_from_slice(&self.pending[self.offset..self.offset + n]);
+            self.offset += n;
+            written += n;
+        }
+
+        Ok(written)
+    }
+}
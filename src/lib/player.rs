@@ -1,58 +1,210 @@
-use std::{env, io};
+use std::{env, fs, io};
 use std::clone::Clone;
 use std::env::VarError;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{
-    Arc,
-    mpsc::{Receiver, sync_channel, SyncSender}, Mutex,
-};
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use byteorder::{ByteOrder, LittleEndian};
-use librespot::audio::AudioPacket;
 use librespot::connect::spirc::Spirc;
 use librespot::core::{
     authentication::Credentials,
     cache::Cache,
     config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl},
-    session::Session,
+    session::{Session, SessionError},
 };
 use librespot::playback::{
-    audio_backend,
     config::{NormalisationMethod, NormalisationType},
     config::Bitrate,
     config::PlayerConfig,
     mixer::{AudioFilter, Mixer, MixerConfig},
-    player::{Player, PlayerEventChannel},
+    player::{Player, PlayerEvent},
 };
-use librespot::protocol::authentication::AuthenticationType;
 use songbird::tracks::TrackCommand::Volume;
-use spotify_oauth::{SpotifyAuth, SpotifyCallback, SpotifyScope};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::sleep;
+
+use crate::lib::auth::{OAuthMode, TokenStore};
+use crate::lib::cache::CacheSettings;
+use crate::lib::sink::{BackpressurePolicy, EmittedSink, SinkEvent};
+use crate::lib::web_api::RestorePoint;
+
+/// How the bot shows up in the Spotify Connect device list.
+#[derive(Clone, Debug)]
+pub struct ConnectSettings {
+    /// Device name, `{guild_name}` and `{guild_id}` are filled in
+    pub name: String,
+    pub guild_id: String,
+    pub guild_name: Option<String>,
+    pub device_type: DeviceType,
+    /// Initial volume in percent
+    pub volume: u8,
+    pub volume_ctrl: VolumeCtrl,
+    pub autoplay: bool,
+}
 
-pub struct SpotifyPlayer {
-    player_config: PlayerConfig,
-    pub emitted_sink: EmittedSink,
-    pub session: Session,
-    pub spirc: Option<Box<Spirc>>,
-    pub event_channel: Option<Arc<tokio::sync::Mutex<PlayerEventChannel>>>,
+impl ConnectSettings {
+    /// Defaults, overridden by `CONNECT_NAME`, `CONNECT_DEVICE_TYPE`, `CONNECT_VOLUME`,
+    /// `CONNECT_VOLUME_CTRL` and `CONNECT_AUTOPLAY`.
+    pub fn from_env(guild_id: String) -> ConnectSettings {
+        let mut settings = ConnectSettings {
+            name: "Groover".to_string(),
+            guild_id,
+            guild_name: None,
+            device_type: DeviceType::AudioDongle,
+            volume: 50,
+            volume_ctrl: VolumeCtrl::default(),
+            autoplay: true,
+        };
+
+        settings.update(
+            env::var("CONNECT_NAME").ok(),
+            env::var("CONNECT_DEVICE_TYPE").ok(),
+            env::var("CONNECT_VOLUME").ok().map(|v| v.parse().expect("Invalid CONNECT_VOLUME")),
+            env::var("CONNECT_VOLUME_CTRL").ok(),
+            env::var("CONNECT_AUTOPLAY").ok().map(|v| v == "1" || v == "true"),
+        ).expect("Invalid Spotify Connect settings");
+
+        settings
+    }
+
+    /// Applies the given changes, leaving `None` fields as they are.
+    pub fn update(
+        &mut self,
+        name: Option<String>,
+        device_type: Option<String>,
+        volume: Option<u8>,
+        volume_ctrl: Option<String>,
+        autoplay: Option<bool>,
+    ) -> Result<(), String> {
+        if let Some(device_type) = device_type {
+            self.device_type = DeviceType::from_str(&device_type)
+                .map_err(|_| format!("unknown device type {}", device_type))?;
+        }
+        if let Some(volume_ctrl) = volume_ctrl {
+            self.volume_ctrl = VolumeCtrl::from_str(&volume_ctrl)
+                .map_err(|_| format!("unknown volume control {}", volume_ctrl))?;
+        }
+        if let Some(volume) = volume {
+            self.volume = volume.min(100);
+        }
+        if let Some(name) = name {
+            self.name = name;
+        }
+        if let Some(autoplay) = autoplay {
+            self.autoplay = autoplay;
+        }
+
+        Ok(())
+    }
+
+    pub fn device_name(&self) -> String {
+        self.name
+            .replace("{guild_name}", self.guild_name.as_deref().unwrap_or(&self.guild_id))
+            .replace("{guild_id}", &self.guild_id)
+    }
+
+    fn connect_config(&self) -> ConnectConfig {
+        ConnectConfig {
+            name: self.device_name(),
+            device_type: self.device_type,
+            volume: (std::u16::MAX as u32 * self.volume as u32 / 100) as u16,
+            autoplay: self.autoplay,
+            volume_ctrl: self.volume_ctrl.clone(),
+        }
+    }
 }
 
-pub struct EmittedSink {
-    sender: Arc<SyncSender<u8>>,
-    pub receiver: Arc<Mutex<Receiver<u8>>>,
+/// What to do when playback moves from us to another Connect device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceLostPolicy {
+    /// Leave the voice channel
+    Leave,
+    /// Stay in the channel and say nothing
+    Silent,
+    /// Stay in the channel and tell the operator
+    Notify,
 }
 
-impl EmittedSink {
-    fn new() -> EmittedSink {
-        let (sender, receiver) = sync_channel::<u8>(64);
+impl FromStr for DeviceLostPolicy {
+    type Err = String;
 
-        EmittedSink {
-            sender: Arc::new(sender),
-            receiver: Arc::new(Mutex::new(receiver)),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "leave" => Ok(DeviceLostPolicy::Leave),
+            "silent" => Ok(DeviceLostPolicy::Silent),
+            "notify" => Ok(DeviceLostPolicy::Notify),
+            _ => Err(format!("unknown device lost policy {}", s)),
         }
     }
 }
 
+/// The Spotify quality worth streaming into a voice channel with the given bitrate.
+pub fn bitrate_for_channel(bits_per_second: u32) -> Bitrate {
+    match bits_per_second {
+        0..=64_000 => Bitrate::Bitrate96,
+        64_001..=128_000 => Bitrate::Bitrate160,
+        _ => Bitrate::Bitrate320,
+    }
+}
+
+// Where librespot keeps the reusable credentials inside the cache directory
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+// Tries to reach the access point before giving up on it at startup
+const CONNECT_ATTEMPTS: u32 = 5;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Opens a session, retrying while the access point can't be reached. Rejected
+/// credentials fail right away.
+async fn connect(credentials: Credentials, cache: Option<Cache>) -> Result<Session, SessionError> {
+    let mut delay = CONNECT_RETRY_DELAY;
+    let mut attempts = 1;
+
+    loop {
+        match Session::connect(SessionConfig::default(), credentials.clone(), cache.clone()).await {
+            Err(SessionError::IoError(e)) if attempts < CONNECT_ATTEMPTS => {
+                println!("Could not reach Spotify (attempt {}): {}", attempts, e);
+                sleep(delay).await;
+                delay *= 2;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub struct SpotifyPlayer {
+    player_config: PlayerConfig,
+    pub emitted_sink: EmittedSink,
+    /// `None` while we started offline and haven't reached Spotify yet
+    pub session: Option<Session>,
+    cache: Option<Cache>,
+    pub cache_settings: CacheSettings,
+    credentials_dir: Option<PathBuf>,
+    /// Only set when we had to log in with an access token instead of cached credentials
+    pub tokens: Option<TokenStore>,
+    pub spirc: Option<Box<Spirc>>,
+    // Connect was asked for while there was no session, it starts with the next one
+    connect_pending: bool,
+    /// Events of whichever player Connect is running, forwarded so rebuilding Connect
+    /// never has to wait on the reader
+    pub player_events: Arc<tokio::sync::Mutex<UnboundedReceiver<PlayerEvent>>>,
+    player_events_tx: UnboundedSender<PlayerEvent>,
+    pub sink_events: Arc<tokio::sync::Mutex<UnboundedReceiver<SinkEvent>>>,
+    playback: Option<PlaybackPosition>,
+    pub connect_settings: ConnectSettings,
+}
+
+/// Last known track and position, to pick playback up again after a reconnect.
+struct PlaybackPosition {
+    track_uri: String,
+    position_ms: u32,
+    since: Instant,
+    playing: bool,
+}
+
 pub struct SoftMixer {
     volume: Arc<AtomicUsize>,
 }
@@ -96,63 +248,6 @@ impl AudioFilter for SoftVolumeApplier {
     }
 }
 
-impl audio_backend::Sink for EmittedSink {
-    fn start(&mut self) -> std::result::Result<(), std::io::Error> {
-        Ok(())
-    }
-
-    fn stop(&mut self) -> std::result::Result<(), std::io::Error> {
-        Ok(())
-    }
-
-    fn write(&mut self, packet: &AudioPacket) -> std::result::Result<(), std::io::Error> {
-        let resampled = samplerate::convert(
-            44100,
-            48000,
-            2,
-            samplerate::ConverterType::Linear,
-            packet.samples(),
-        )
-            .unwrap();
-
-        let sender = self.sender.clone();
-
-        for i in resampled {
-            let mut new = [0, 0, 0, 0];
-
-            LittleEndian::write_f32_into(&[i], &mut new);
-
-            for j in new.iter() {
-                sender.send(*j).unwrap();
-            }
-        }
-
-        Ok(())
-    }
-}
-
-impl io::Read for EmittedSink {
-    fn read(&mut self, buff: &mut [u8]) -> Result<usize, io::Error> {
-        let receiver = self.receiver.lock().unwrap();
-
-        #[allow(clippy::needless_range_loop)]
-        for i in 0..buff.len() {
-            buff[i] = receiver.recv().unwrap();
-        }
-
-        Ok(buff.len())
-    }
-}
-
-impl Clone for EmittedSink {
-    fn clone(&self) -> EmittedSink {
-        EmittedSink {
-            receiver: self.receiver.clone(),
-            sender: self.sender.clone(),
-        }
-    }
-}
-
 /*pub struct SpotifyPlayerKey;
 impl TypeMapKey for SpotifyPlayerKey {
     type Value = Arc<tokio::sync::Mutex<SpotifyPlayer>>;
@@ -161,49 +256,57 @@ impl TypeMapKey for SpotifyPlayerKey {
 impl SpotifyPlayer {
     pub async fn new(
         quality: Bitrate,
-        cache_dir: Option<String>,
+        cache_settings: CacheSettings,
+        backpressure: BackpressurePolicy,
+        oauth_mode: OAuthMode,
+        connect_settings: ConnectSettings,
+        offline_fallback: bool,
     ) -> SpotifyPlayer {
-        let tok = match env::var("TOKEN") {
-            Ok(token) => {
-                token
-            }
-            Err(_) => {
-                let auth = SpotifyAuth::new_from_env("code".into(), vec![SpotifyScope::Streaming, SpotifyScope::UserReadPlaybackState, SpotifyScope::UserModifyPlaybackState, SpotifyScope::UserReadCurrentlyPlaying], false);
-                let auth_url = auth.authorize_url().expect("auth url");
-
-                println!("{}", auth_url);
-
-                let mut buffer = String::new();
-                std::io::stdin().read_line(&mut buffer);
-
-                // Convert the given callback URL into a token.
-                let token = SpotifyCallback::from_str(buffer.trim()).unwrap()
-                    .convert_into_token(auth.client_id, auth.client_secret, auth.redirect_uri).await.expect("get token");
-                token.access_token
-            }
-        };
-        let credentials = Credentials {
-            username: "".into(),
-            auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
-            auth_data: tok.into_bytes(),
-        };
-
-        let session_config = SessionConfig::default();
-
         let mut cache: Option<Cache> = None;
 
-        // 4 GB
-        let mut cache_limit: u64 = 10;
-        cache_limit = cache_limit.pow(10);
-        cache_limit *= 4;
-
-        if let Ok(c) = Cache::new(cache_dir.clone(), cache_dir, Some(cache_limit)) {
+        if let Ok(c) = Cache::new(
+            cache_settings.credentials_dir.clone(),
+            cache_settings.audio_dir.clone(),
+            Some(cache_settings.size_limit),
+        ) {
             cache = Some(c);
         }
 
-        let session = Session::connect(session_config, credentials, cache)
-            .await
-            .expect("Error creating session");
+        // librespot saves reusable credentials into the cache after every login
+        let mut session = None;
+        let mut log_in_again = true;
+        if let Some(credentials) = cache.as_ref().and_then(|c| c.credentials()) {
+            println!("Logging in with cached credentials for {}", credentials.username);
+            match connect(credentials, cache.clone()).await {
+                Ok(s) => {
+                    session = Some(s);
+                    log_in_again = false;
+                }
+                Err(SessionError::AuthenticationError(e)) => {
+                    println!("Cached credentials were rejected, logging in again: {:?}", e);
+                }
+                // With something cached to play there is no need to fail, the session
+                // is retried in the background
+                Err(e) if offline_fallback => {
+                    println!("Could not reach Spotify, starting offline: {}", e);
+                    log_in_again = false;
+                }
+                Err(e) => panic!("Could not reach Spotify: {}", e),
+            }
+        }
+
+        let mut tokens = None;
+        if log_in_again {
+            let store = TokenStore::login(cache_settings.credentials_dir.clone(), oauth_mode).await;
+            match connect(store.credentials(), cache.clone()).await {
+                Ok(s) => session = Some(s),
+                Err(SessionError::IoError(e)) if offline_fallback => {
+                    println!("Could not reach Spotify, starting offline: {}", e);
+                }
+                Err(e) => panic!("Error creating session: {}", e),
+            }
+            tokens = Some(store);
+        }
 
         let player_config = PlayerConfig {
             bitrate: quality,
@@ -219,63 +322,231 @@ impl SpotifyPlayer {
             passthrough: false,
         };
 
-        let emitted_sink = EmittedSink::new();
+        let (emitted_sink, sink_events) = EmittedSink::new(backpressure);
 
-        let cloned_sink = emitted_sink.clone();
-
-        let (_player, rx) = Player::new(player_config.clone(), session.clone(), None, move || {
-            Box::new(cloned_sink)
-        });
+        let (player_events_tx, player_events) = unbounded_channel();
 
         SpotifyPlayer {
             player_config,
             emitted_sink,
             session,
+            cache,
+            credentials_dir: cache_settings.credentials_dir.clone().map(PathBuf::from),
+            cache_settings,
+            tokens,
             spirc: None,
-            event_channel: Some(Arc::new(tokio::sync::Mutex::new(rx))),
+            connect_pending: false,
+            player_events: Arc::new(tokio::sync::Mutex::new(player_events)),
+            player_events_tx,
+            sink_events: Arc::new(tokio::sync::Mutex::new(sink_events)),
+            playback: None,
+            connect_settings,
+        }
+    }
+
+    /// Keeps track of what is playing, call this with every player event.
+    pub fn track_playback(&mut self, event: &PlayerEvent) {
+        match event {
+            PlayerEvent::Playing { track_id, position_ms, .. }
+            | PlayerEvent::Paused { track_id, position_ms, .. } => {
+                self.playback = Some(PlaybackPosition {
+                    track_uri: track_id.to_uri(),
+                    position_ms: *position_ms,
+                    since: Instant::now(),
+                    playing: matches!(event, PlayerEvent::Playing { .. }),
+                });
+            }
+            PlayerEvent::Stopped { .. } => self.playback = None,
+            _ => {}
         }
     }
 
+    /// Where playback is right now, `None` when nothing is loaded.
+    pub fn restore_point(&self) -> Option<RestorePoint> {
+        self.playback.as_ref().map(|p| {
+            let elapsed = if p.playing { p.since.elapsed().as_millis() as u32 } else { 0 };
+
+            RestorePoint {
+                track_uri: p.track_uri.clone(),
+                position_ms: p.position_ms + elapsed,
+                playing: p.playing,
+            }
+        })
+    }
+
     pub async fn enable_connect(&mut self) {
-        let config = ConnectConfig {
-            name: "Pog ass bot".to_string(),
-            device_type: DeviceType::AudioDongle,
-            volume: std::u16::MAX / 2,
-            autoplay: true,
-            volume_ctrl: VolumeCtrl::default(),
+        let session = match self.session.clone() {
+            Some(session) => session,
+            None => {
+                println!("Not connected to Spotify, Connect starts once the session is back");
+                self.connect_pending = true;
+                return;
+            }
         };
+        self.connect_pending = false;
+
+        let config = self.connect_settings.connect_config();
 
         let mixer = Box::new(SoftMixer { volume: Arc::new(Default::default()) });
 
         let cloned_sink = self.emitted_sink.clone();
 
-        let (player, player_events) = Player::new(
+        let (player, mut player_events) = Player::new(
             self.player_config.clone(),
-            self.session.clone(),
+            session.clone(),
             mixer.get_audio_filter(),
             move || Box::new(cloned_sink),
         );
 
-        let cloned_session = self.session.clone();
-
-        let (spirc, task) = Spirc::new(config, cloned_session, player, mixer);
+        let (spirc, task) = Spirc::new(config, session, player, mixer);
 
         let handle = tokio::runtime::Handle::current();
         handle.spawn(async {
             task.await;
         });
 
-        self.spirc = Some(Box::new(spirc));
+        // Ends by itself once the player is dropped on shutdown. Flushing happens here
+        // rather than in the event loop, which may wait on the player lock while librespot
+        // already writes audio for the new position.
+        let forward = self.player_events_tx.clone();
+        let sink = self.emitted_sink.clone();
+        handle.spawn(async move {
+            let mut playing = None;
+
+            while let Some(event) = player_events.recv().await {
+                // Drop whatever is still queued for songbird when the track changes,
+                // playback stops or pauses, or a seek happens while playing
+                match event {
+                    PlayerEvent::Loading { .. }
+                    | PlayerEvent::Stopped { .. }
+                    | PlayerEvent::Paused { .. } => {
+                        sink.flush();
+                        playing = None;
+                    }
+                    // Another track is a gapless change, its start is already queued
+                    PlayerEvent::Playing { track_id, .. } => {
+                        if playing == Some(track_id) {
+                            sink.flush();
+                        }
+                        playing = Some(track_id);
+                    }
+                    _ => {}
+                }
+
+                if forward.send(event).is_err() {
+                    break;
+                }
+            }
+        });
 
-        let mut channel_lock = self.event_channel.as_ref().unwrap().lock().await;
-        *channel_lock = player_events;
+        self.spirc = Some(Box::new(spirc));
     }
 
     pub async fn disable_connect(&mut self) {
-        if let Some(spirc) = self.spirc.as_ref() {
+        self.connect_pending = false;
+
+        if let Some(spirc) = self.spirc.take() {
             spirc.shutdown();
+        }
+    }
+
+    /// Switches to new Connect settings. A running Connect device is restarted to pick them
+    /// up, which stops playback on it.
+    pub async fn set_connect_settings(&mut self, settings: ConnectSettings) {
+        self.connect_settings = settings;
+
+        if self.spirc.is_some() {
+            self.disable_connect().await;
+            self.emitted_sink.flush();
+            self.enable_connect().await;
+        }
+    }
+
+    pub fn cache(&self) -> Option<Cache> {
+        self.cache.clone()
+    }
+
+    pub fn bitrate(&self) -> Bitrate {
+        self.player_config.bitrate
+    }
 
-            self.event_channel.as_ref().unwrap().lock().await.close();
+    /// Switches the streaming bitrate. A running Connect device is rebuilt with a player
+    /// on the new config, which stops playback on it.
+    pub async fn set_bitrate(&mut self, bitrate: Bitrate) {
+        self.player_config.bitrate = bitrate;
+
+        if self.spirc.is_some() {
+            self.disable_connect().await;
+            self.emitted_sink.flush();
+            self.enable_connect().await;
+        }
+    }
+
+    /// Deletes the cached librespot credentials and the saved OAuth token, the next start
+    /// has to log in again. The running session is left alone.
+    pub fn forget_credentials(&mut self) -> io::Result<()> {
+        if let Some(dir) = self.credentials_dir.as_ref() {
+            match fs::remove_file(dir.join(CREDENTIALS_FILE)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
         }
+
+        if let Some(tokens) = self.tokens.as_ref() {
+            tokens.forget()?;
+        }
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Opens a new session with the cached credentials or the current access token, for
+    /// when the old one was invalidated. The player and Spotify Connect are rebuilt on it
+    /// if Connect was enabled. `player` is only locked around connecting, not during it.
+    pub async fn reauthenticate(player: &tokio::sync::Mutex<SpotifyPlayer>) -> Result<(), String> {
+        let (credentials, cache) = {
+            let player = player.lock().await;
+            let credentials = player.cache.as_ref()
+                .and_then(|c| c.credentials())
+                .or_else(|| player.tokens.as_ref().map(|t| t.credentials()))
+                .ok_or_else(|| "no credentials to log in with".to_string())?;
+
+            (credentials, player.cache.clone())
+        };
+
+        let session = Session::connect(SessionConfig::default(), credentials, cache)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        player.lock().await.replace_session(session).await;
+
+        Ok(())
+    }
+
+    async fn replace_session(&mut self, session: Session) {
+        let was_connected = self.spirc.is_some() || self.connect_pending;
+        self.disable_connect().await;
+        self.emitted_sink.flush();
+
+        self.session = Some(session);
+
+        if was_connected {
+            self.enable_connect().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_quality_for_channel_bitrate() {
+        assert_eq!(bitrate_for_channel(8_000), Bitrate::Bitrate96);
+        assert_eq!(bitrate_for_channel(64_000), Bitrate::Bitrate96);
+        assert_eq!(bitrate_for_channel(64_001), Bitrate::Bitrate160);
+        assert_eq!(bitrate_for_channel(96_000), Bitrate::Bitrate160);
+        assert_eq!(bitrate_for_channel(128_000), Bitrate::Bitrate160);
+        assert_eq!(bitrate_for_channel(128_001), Bitrate::Bitrate320);
+        assert_eq!(bitrate_for_channel(384_000), Bitrate::Bitrate320);
+    }
+}
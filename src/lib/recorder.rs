@@ -0,0 +1,311 @@
+use std::{fs, io, thread};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use audiopus::{Application, Channels, SampleRate};
+use audiopus::coder::Encoder;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::lib::pipeline::{Frame, FRAME_SAMPLES, FrameKind, FrameReader, ogg_opus_head, ogg_opus_tags, OPUS_PRE_SKIP, Pipeline};
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: u16 = 2;
+
+// flacenc keeps a whole file in memory, about 23MB a minute, so FLAC files always rotate
+const FLAC_MAX_FILE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    Wav,
+    Flac,
+    Opus,
+}
+
+impl RecordingFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Wav => "wav",
+            RecordingFormat::Flac => "flac",
+            RecordingFormat::Opus => "opus",
+        }
+    }
+}
+
+pub struct RecordingConfig {
+    pub format: RecordingFormat,
+    pub directory: PathBuf,
+    /// Stop once this much audio has been recorded
+    pub max_duration: Option<Duration>,
+    /// Start a new file once the current one holds this much audio, FLAC files rotate
+    /// after 5 minutes at most
+    pub rotate_after: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct RecordingSummary {
+    pub files: Vec<PathBuf>,
+    pub error: Option<io::Error>,
+}
+
+/// Records the audio the pipeline hands to songbird, silence while paused included.
+pub struct Recorder {
+    stop: Arc<AtomicBool>,
+}
+
+impl Recorder {
+    /// Starts recording, `done` receives the written files once the recording ends.
+    pub fn start(
+        pipeline: &Pipeline,
+        config: RecordingConfig,
+        done: UnboundedSender<RecordingSummary>,
+    ) -> io::Result<Recorder> {
+        fs::create_dir_all(&config.directory)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let kind = match config.format {
+            RecordingFormat::Opus => FrameKind::Opus,
+            _ => FrameKind::Pcm,
+        };
+        let frames = pipeline.subscribe(kind);
+
+        let worker_stop = stop.clone();
+        thread::spawn(move || {
+            let summary = record(frames, config, &worker_stop);
+            worker_stop.store(true, Ordering::Relaxed);
+            let _ = done.send(summary);
+        });
+
+        Ok(Recorder { stop })
+    }
+
+    /// Asks the recording to finish, the summary is delivered through `done`.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+fn record(frames: FrameReader, config: RecordingConfig, stop: &AtomicBool) -> RecordingSummary {
+    let mut files = Vec::new();
+    let mut writer: Option<Box<dyn RecordingWriter>> = None;
+    let mut file_samples: u64 = 0;
+    let mut total_samples: u64 = 0;
+
+    let to_samples = |d: Duration| d.as_millis() as u64 * SAMPLE_RATE as u64 * CHANNELS as u64 / 1000;
+    let max_samples = config.max_duration.map(to_samples);
+    let rotate_after = match config.format {
+        RecordingFormat::Flac => Some(config.rotate_after.map_or(FLAC_MAX_FILE_DURATION, |d| d.min(FLAC_MAX_FILE_DURATION))),
+        _ => config.rotate_after,
+    };
+    let rotate_samples = rotate_after.map(to_samples);
+
+    let result = (|| -> io::Result<()> {
+        while !stop.load(Ordering::Relaxed) {
+            let frame = match frames.recv_timeout(Duration::from_millis(200)) {
+                Ok(frame) => frame,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            if writer.is_none() {
+                let path = next_path(&config.directory, config.format, files.len());
+                writer = Some(open_writer(config.format, &path)?);
+                files.push(path);
+                file_samples = 0;
+            }
+
+            writer.as_mut().unwrap().write(&frame)?;
+            file_samples += frame.pcm.len() as u64;
+            total_samples += frame.pcm.len() as u64;
+
+            if max_samples.map_or(false, |max| total_samples >= max) {
+                break;
+            }
+
+            if rotate_samples.map_or(false, |rotate| file_samples >= rotate) {
+                writer.take().unwrap().finish()?;
+            }
+        }
+
+        Ok(())
+    })();
+
+    // Close the last file even when writing failed so that what we have is readable
+    let finished = match writer.take() {
+        Some(writer) => writer.finish(),
+        None => Ok(()),
+    };
+
+    RecordingSummary {
+        files,
+        error: result.and(finished).err(),
+    }
+}
+
+fn next_path(directory: &Path, format: RecordingFormat, index: usize) -> PathBuf {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    directory.join(format!("groover-{}-{}.{}", timestamp, index, format.extension()))
+}
+
+fn open_writer(format: RecordingFormat, path: &Path) -> io::Result<Box<dyn RecordingWriter>> {
+    Ok(match format {
+        RecordingFormat::Wav => Box::new(WavRecording::create(path)?),
+        RecordingFormat::Flac => Box::new(FlacRecording::create(path)),
+        RecordingFormat::Opus => Box::new(OpusRecording::create(path)?),
+    })
+}
+
+fn other_error<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+trait RecordingWriter: Send {
+    fn write(&mut self, frame: &Frame) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+struct WavRecording {
+    writer: hound::WavWriter<BufWriter<File>>,
+}
+
+impl WavRecording {
+    fn create(path: &Path) -> io::Result<WavRecording> {
+        let spec = hound::WavSpec {
+            channels: CHANNELS,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        Ok(WavRecording {
+            writer: hound::WavWriter::create(path, spec).map_err(other_error)?,
+        })
+    }
+}
+
+impl RecordingWriter for WavRecording {
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        for sample in frame.pcm.iter() {
+            self.writer.write_sample(*sample).map_err(other_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.writer.finalize().map_err(other_error)
+    }
+}
+
+/// flacenc encodes whole streams, so the samples are kept in memory until the file is
+/// finished. `FLAC_MAX_FILE_DURATION` bounds how many that can be.
+struct FlacRecording {
+    path: PathBuf,
+    samples: Vec<i32>,
+}
+
+impl FlacRecording {
+    fn create(path: &Path) -> FlacRecording {
+        FlacRecording {
+            path: path.to_path_buf(),
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl RecordingWriter for FlacRecording {
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        self.samples.extend(
+            frame.pcm.iter().map(|s| (s.max(-1.0).min(1.0) * i16::MAX as f32) as i32),
+        );
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        use flacenc::component::BitRepr;
+        use flacenc::error::Verify;
+
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| other_error(e))?;
+        let source = flacenc::source::MemSource::from_samples(
+            &self.samples,
+            CHANNELS as usize,
+            16,
+            SAMPLE_RATE as usize,
+        );
+        let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+            .map_err(other_error)?;
+
+        let mut sink = flacenc::bitsink::ByteSink::new();
+        stream.write(&mut sink).map_err(other_error)?;
+
+        fs::write(&self.path, sink.as_slice())
+    }
+}
+
+const OPUS_SERIAL: u32 = 1;
+
+struct OpusRecording {
+    encoder: Encoder,
+    writer: PacketWriter<'static, BufWriter<File>>,
+    granule: u64,
+}
+
+impl OpusRecording {
+    fn create(path: &Path) -> io::Result<OpusRecording> {
+        let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+            .map_err(other_error)?;
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(path)?));
+
+        writer.write_packet(Cow::Owned(ogg_opus_head()), OPUS_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(Cow::Owned(ogg_opus_tags(&[])), OPUS_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(OpusRecording {
+            encoder,
+            writer,
+            granule: OPUS_PRE_SKIP as u64,
+        })
+    }
+
+    fn write_packet(&mut self, packet: Vec<u8>, end: PacketWriteEndInfo) -> io::Result<()> {
+        self.granule += (FRAME_SAMPLES / CHANNELS as usize) as u64;
+
+        self.writer.write_packet(Cow::Owned(packet), OPUS_SERIAL, end, self.granule)
+    }
+}
+
+impl RecordingWriter for OpusRecording {
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        // Subscribed for Opus frames, which always carry their packet
+        match frame.opus.as_ref() {
+            Some(packet) => self.write_packet(packet.to_vec(), PacketWriteEndInfo::NormalPacket),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        // The stream has to end on a packet, close it with a frame of silence
+        let mut packet = vec![0; 4000];
+        let len = self.encoder.encode_float(&[0.0; FRAME_SAMPLES], &mut packet).map_err(other_error)?;
+        packet.truncate(len);
+        self.write_packet(packet, PacketWriteEndInfo::EndStream)?;
+
+        self.writer.inner_mut().flush()
+    }
+}
@@ -0,0 +1,526 @@
+use std::{io, thread};
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    mpsc::{Receiver, sync_channel, SyncSender, TryRecvError, TrySendError}, Mutex,
+};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use librespot::audio::AudioPacket;
+use librespot::playback::audio_backend;
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+// Number of librespot packets that may be queued between the player and songbird
+const SINK_CAPACITY: usize = 16;
+
+// Songbird reads every 20ms, a consumer that has been quiet for longer is gone
+const CONSUMER_TIMEOUT_MS: u64 = 250;
+
+/// What `EmittedSink::write` does when no songbird input is reading the audio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackpressurePolicy {
+    /// Wait for a consumer, this stalls the librespot player thread
+    Block,
+    /// Discard the audio at playback speed, the track keeps progressing
+    Drop,
+    /// Discard the audio and ask for playback to be paused through Spotify Connect
+    Pause,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(BackpressurePolicy::Block),
+            "drop" => Ok(BackpressurePolicy::Drop),
+            "pause" => Ok(BackpressurePolicy::Pause),
+            _ => Err(format!("unknown backpressure policy {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkEvent {
+    /// Audio is being discarded because nobody reads it, playback should be paused
+    ConsumerLost,
+    /// A consumer is reading again after `ConsumerLost`
+    ConsumerReturned,
+}
+
+struct SinkChunk {
+    epoch: u64,
+    data: Vec<u8>,
+    written_at: Instant,
+}
+
+/// Counters for the audio path between `EmittedSink::write` and whatever reads the sink.
+#[derive(Default)]
+struct SinkMetrics {
+    queued_chunks: AtomicU64,
+    queued_bytes: AtomicU64,
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+    resample_ns_last: AtomicU64,
+    resample_ns_total: AtomicU64,
+    packets: AtomicU64,
+    latency_us_last: AtomicU64,
+    latency_us_total: AtomicU64,
+    latency_samples: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SinkMetricsSnapshot {
+    /// Chunks written but not read yet, each one is a librespot packet
+    pub queued_chunks: u64,
+    pub queued_bytes: u64,
+    pub capacity_chunks: u64,
+    /// Reads padded with silence while a track was playing
+    pub underruns: u64,
+    /// Packets discarded because nothing was consuming the sink
+    pub overruns: u64,
+    pub packets: u64,
+    pub resample_us_last: f64,
+    pub resample_us_avg: f64,
+    /// Time from a packet being written to songbird reading the frame it starts in
+    pub latency_ms_last: f64,
+    pub latency_ms_avg: f64,
+}
+
+impl SinkMetrics {
+    fn dequeued(&self, chunk: &SinkChunk) {
+        self.queued_chunks.fetch_sub(1, Ordering::Relaxed);
+        self.queued_bytes.fetch_sub(chunk.data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+struct SinkReader {
+    receiver: Receiver<SinkChunk>,
+    pending: Vec<u8>,
+    pending_epoch: u64,
+    pending_written_at: Option<Instant>,
+    offset: usize,
+}
+
+impl SinkReader {
+    fn clear(&mut self, metrics: &SinkMetrics) {
+        self.pending.clear();
+        self.offset = 0;
+
+        while let Ok(chunk) = self.receiver.try_recv() {
+            metrics.dequeued(&chunk);
+        }
+    }
+}
+
+pub struct EmittedSink {
+    sender: Arc<SyncSender<SinkChunk>>,
+    reader: Arc<Mutex<SinkReader>>,
+    // Bumped on every flush, chunks written under an older epoch are discarded
+    epoch: Arc<AtomicU64>,
+    // Set by librespot while a track is playing, underruns only count then
+    active: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+    metrics: Arc<SinkMetrics>,
+    policy: BackpressurePolicy,
+    clock: Instant,
+    // Milliseconds on `clock` of the last read, refreshed on attach to give songbird time to start
+    last_read: Arc<AtomicU64>,
+    attached: Arc<AtomicBool>,
+    starved: Arc<AtomicBool>,
+    events: UnboundedSender<SinkEvent>,
+}
+
+impl EmittedSink {
+    pub fn new(policy: BackpressurePolicy) -> (EmittedSink, UnboundedReceiver<SinkEvent>) {
+        let (sender, receiver) = sync_channel::<SinkChunk>(SINK_CAPACITY);
+        let (events, events_rx) = unbounded_channel();
+
+        let sink = EmittedSink {
+            sender: Arc::new(sender),
+            reader: Arc::new(Mutex::new(SinkReader {
+                receiver,
+                pending: Vec::new(),
+                pending_epoch: 0,
+                pending_written_at: None,
+                offset: 0,
+            })),
+            epoch: Arc::new(AtomicU64::new(0)),
+            active: Arc::new(AtomicBool::new(false)),
+            closed: Arc::new(AtomicBool::new(false)),
+            metrics: Arc::new(SinkMetrics::default()),
+            policy,
+            clock: Instant::now(),
+            last_read: Arc::new(AtomicU64::new(0)),
+            attached: Arc::new(AtomicBool::new(false)),
+            starved: Arc::new(AtomicBool::new(false)),
+            events,
+        };
+
+        (sink, events_rx)
+    }
+
+    /// Marks whether something is reading this sink in real time.
+    pub fn set_consumer_attached(&self, attached: bool) {
+        if attached {
+            self.last_read.store(self.now_ms(), Ordering::Relaxed);
+        }
+
+        self.attached.store(attached, Ordering::Relaxed);
+    }
+
+    pub fn is_consumer_live(&self) -> bool {
+        self.attached.load(Ordering::Relaxed)
+            && self.now_ms().saturating_sub(self.last_read.load(Ordering::Relaxed)) < CONSUMER_TIMEOUT_MS
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.clock.elapsed().as_millis() as u64
+    }
+
+    fn discard(&self, samples: usize) {
+        self.metrics.overruns.fetch_add(1, Ordering::Relaxed);
+
+        if self.policy == BackpressurePolicy::Pause && !self.starved.swap(true, Ordering::SeqCst) {
+            let _ = self.events.send(SinkEvent::ConsumerLost);
+        }
+
+        // Keep librespot at playback speed so the position matches what Spotify shows
+        thread::sleep(Duration::from_micros(samples as u64 * 1_000_000 / 2 / 48000));
+    }
+
+    /// Drops all audio that has been written but not read yet, so that skips,
+    /// seeks and pauses are heard immediately instead of after the queued audio.
+    pub fn flush(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+
+        // If the reader is busy it will skip the stale chunks on its own
+        if let Ok(mut reader) = self.reader.try_lock() {
+            reader.clear(&self.metrics);
+        }
+    }
+
+    /// Incremented by every flush.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Ends the stream, queued audio is discarded and readers get end-of-stream.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.flush();
+    }
+
+    /// Records how long the audio written at `written_at` took to reach songbird.
+    pub fn record_latency(&self, written_at: Instant) {
+        let latency_us = written_at.elapsed().as_micros() as u64;
+        self.metrics.latency_us_last.store(latency_us, Ordering::Relaxed);
+        self.metrics.latency_us_total.fetch_add(latency_us, Ordering::Relaxed);
+        self.metrics.latency_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Like `io::Read::read`, but also returns when the first byte read was written.
+    /// `None` when the read started with silence.
+    pub fn read_timed(&mut self, buff: &mut [u8]) -> io::Result<(usize, Option<Instant>)> {
+        let mut reader = self.reader.lock().unwrap();
+        let mut written = 0;
+        let mut written_at = None;
+
+        self.last_read.store(self.now_ms(), Ordering::Relaxed);
+
+        if self.attached.load(Ordering::Relaxed) && self.starved.swap(false, Ordering::SeqCst) {
+            let _ = self.events.send(SinkEvent::ConsumerReturned);
+        }
+
+        while written < buff.len() {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+
+            if reader.pending_epoch < epoch {
+                reader.pending.clear();
+                reader.offset = 0;
+            }
+
+            if reader.offset < reader.pending.len() {
+                let n = (buff.len() - written).min(reader.pending.len() - reader.offset);
+                let offset = reader.offset;
+
+                if written == 0 {
+                    written_at = reader.pending_written_at;
+                }
+
+                buff[written..written + n].copy_from_slice(&reader.pending[offset..offset + n]);
+                reader.offset += n;
+                written += n;
+                continue;
+            }
+
+            // Never block the songbird mixer, pad with silence until librespot catches up
+            let chunk = match reader.receiver.try_recv() {
+                Ok(chunk) => chunk,
+                Err(TryRecvError::Empty) if !self.closed.load(Ordering::SeqCst) => {
+                    for b in buff[written..].iter_mut() {
+                        *b = 0;
+                    }
+
+                    if self.active.load(Ordering::Relaxed) {
+                        self.metrics.underruns.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    return Ok((buff.len(), written_at));
+                }
+                // Player shut down, report end-of-stream after what is left
+                Err(_) => return Ok((written, written_at)),
+            };
+
+            self.metrics.dequeued(&chunk);
+
+            if chunk.epoch < epoch {
+                continue;
+            }
+
+            reader.pending = chunk.data;
+            reader.pending_epoch = chunk.epoch;
+            reader.pending_written_at = Some(chunk.written_at);
+            reader.offset = 0;
+        }
+
+        Ok((written, written_at))
+    }
+
+    pub fn metrics(&self) -> SinkMetricsSnapshot {
+        let m = &self.metrics;
+        let avg = |total: &AtomicU64, count: &AtomicU64| {
+            total.load(Ordering::Relaxed) as f64 / count.load(Ordering::Relaxed).max(1) as f64
+        };
+
+        SinkMetricsSnapshot {
+            queued_chunks: m.queued_chunks.load(Ordering::Relaxed),
+            queued_bytes: m.queued_bytes.load(Ordering::Relaxed),
+            capacity_chunks: SINK_CAPACITY as u64,
+            underruns: m.underruns.load(Ordering::Relaxed),
+            overruns: m.overruns.load(Ordering::Relaxed),
+            packets: m.packets.load(Ordering::Relaxed),
+            resample_us_last: m.resample_ns_last.load(Ordering::Relaxed) as f64 / 1000.0,
+            resample_us_avg: avg(&m.resample_ns_total, &m.packets) / 1000.0,
+            latency_ms_last: m.latency_us_last.load(Ordering::Relaxed) as f64 / 1000.0,
+            latency_ms_avg: avg(&m.latency_us_total, &m.latency_samples) / 1000.0,
+        }
+    }
+}
+
+impl audio_backend::Sink for EmittedSink {
+    fn start(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.active.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.active.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> std::result::Result<(), std::io::Error> {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let written_at = Instant::now();
+
+        let resampled = samplerate::convert(
+            44100,
+            48000,
+            2,
+            samplerate::ConverterType::Linear,
+            packet.samples(),
+        )
+            .unwrap();
+
+        let resample_ns = written_at.elapsed().as_nanos() as u64;
+        self.metrics.resample_ns_last.store(resample_ns, Ordering::Relaxed);
+        self.metrics.resample_ns_total.fetch_add(resample_ns, Ordering::Relaxed);
+        self.metrics.packets.fetch_add(1, Ordering::Relaxed);
+
+        let mut data = vec![0; resampled.len() * 4];
+        LittleEndian::write_f32_into(&resampled, &mut data);
+
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let samples = resampled.len();
+        let bytes = data.len() as u64;
+        let mut chunk = SinkChunk { epoch, data, written_at };
+
+        loop {
+            if self.policy != BackpressurePolicy::Block && !self.is_consumer_live() {
+                self.discard(samples);
+                return Ok(());
+            }
+
+            // Counted before sending so the reader never sees the gauge go negative
+            self.metrics.queued_chunks.fetch_add(1, Ordering::Relaxed);
+            self.metrics.queued_bytes.fetch_add(bytes, Ordering::Relaxed);
+
+            chunk = match self.sender.try_send(chunk) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(chunk)) => {
+                    self.metrics.dequeued(&chunk);
+                    chunk
+                }
+                // The reader can only be gone if the whole sink is being torn down
+                Err(TrySendError::Disconnected(chunk)) => {
+                    self.metrics.dequeued(&chunk);
+                    return Ok(());
+                }
+            };
+
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+impl io::Read for EmittedSink {
+    fn read(&mut self, buff: &mut [u8]) -> Result<usize, io::Error> {
+        self.read_timed(buff).map(|(n, _)| n)
+    }
+}
+
+impl Clone for EmittedSink {
+    fn clone(&self) -> EmittedSink {
+        EmittedSink {
+            sender: self.sender.clone(),
+            reader: self.reader.clone(),
+            epoch: self.epoch.clone(),
+            active: self.active.clone(),
+            closed: self.closed.clone(),
+            metrics: self.metrics.clone(),
+            policy: self.policy,
+            clock: self.clock,
+            last_read: self.last_read.clone(),
+            attached: self.attached.clone(),
+            starved: self.starved.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use librespot::playback::audio_backend::Sink;
+
+    use super::*;
+
+    // Queues `data` the way `write` does, without resampling
+    fn push(sink: &EmittedSink, data: &[u8]) {
+        push_at(sink, sink.epoch(), data);
+    }
+
+    fn push_at(sink: &EmittedSink, epoch: u64, data: &[u8]) {
+        sink.metrics.queued_chunks.fetch_add(1, Ordering::Relaxed);
+        sink.metrics.queued_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        sink.sender.try_send(SinkChunk { epoch, data: data.to_vec(), written_at: Instant::now() }).unwrap();
+    }
+
+    fn read(sink: &mut EmittedSink, len: usize) -> (usize, Vec<u8>) {
+        let mut buff = vec![0xff; len];
+        let n = sink.read(&mut buff).unwrap();
+        (n, buff)
+    }
+
+    #[test]
+    fn reads_chunks_across_boundaries() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        push(&sink, &[1, 2, 3]);
+        push(&sink, &[4, 5, 6]);
+
+        assert_eq!(read(&mut sink, 4), (4, vec![1, 2, 3, 4]));
+        assert_eq!(read(&mut sink, 2), (2, vec![5, 6]));
+        assert_eq!(sink.metrics().queued_chunks, 0);
+    }
+
+    #[test]
+    fn pads_with_silence_when_empty() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        push(&sink, &[1, 2]);
+
+        assert_eq!(read(&mut sink, 4), (4, vec![1, 2, 0, 0]));
+        assert_eq!(read(&mut sink, 2), (2, vec![0, 0]));
+    }
+
+    #[test]
+    fn counts_underruns_only_while_playing() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+
+        read(&mut sink, 4);
+        assert_eq!(sink.metrics().underruns, 0);
+
+        sink.start().unwrap();
+        read(&mut sink, 4);
+        assert_eq!(sink.metrics().underruns, 1);
+
+        sink.stop().unwrap();
+        read(&mut sink, 4);
+        assert_eq!(sink.metrics().underruns, 1);
+    }
+
+    #[test]
+    fn flush_drops_queued_audio() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        push(&sink, &[1, 2, 3, 4]);
+        push(&sink, &[5, 6]);
+
+        sink.flush();
+
+        assert_eq!(read(&mut sink, 4), (4, vec![0, 0, 0, 0]));
+        assert_eq!(sink.metrics().queued_chunks, 0);
+        assert_eq!(sink.metrics().queued_bytes, 0);
+    }
+
+    #[test]
+    fn flush_drops_partially_read_chunk() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        push(&sink, &[1, 2, 3, 4]);
+
+        assert_eq!(read(&mut sink, 2), (2, vec![1, 2]));
+        sink.flush();
+        push(&sink, &[7, 8]);
+
+        assert_eq!(read(&mut sink, 4), (4, vec![7, 8, 0, 0]));
+    }
+
+    #[test]
+    fn skips_chunks_from_older_epochs() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        let stale = sink.epoch();
+        sink.flush();
+
+        // Written while the flush was happening, still carrying the old epoch
+        push_at(&sink, stale, &[1, 2]);
+        push(&sink, &[3, 4]);
+
+        assert_eq!(read(&mut sink, 2), (2, vec![3, 4]));
+    }
+
+    #[test]
+    fn ends_stream_after_close() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        push(&sink, &[1, 2]);
+
+        sink.close();
+
+        assert_eq!(read(&mut sink, 4).0, 0);
+        assert_eq!(read(&mut sink, 4).0, 0);
+    }
+
+    #[test]
+    fn drains_what_arrives_after_close() {
+        let (mut sink, _) = EmittedSink::new(BackpressurePolicy::Drop);
+        sink.close();
+        push(&sink, &[1, 2]);
+
+        assert_eq!(read(&mut sink, 4), (2, vec![1, 2, 0xff, 0xff]));
+        assert_eq!(read(&mut sink, 4).0, 0);
+    }
+}
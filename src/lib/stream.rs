@@ -0,0 +1,200 @@
+use std::{io, thread};
+use std::borrow::Cow;
+use std::io::Write;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::lib::http;
+use crate::lib::pipeline::{FRAME_SAMPLES, FrameKind, FrameReader, ogg_opus_head, ogg_opus_tags, OPUS_PRE_SKIP, Pipeline};
+
+// Audio bytes between two ICY metadata blocks
+const ICY_METAINT: usize = 16000;
+
+// Frames per Ogg page, 100ms keeps the latency low without too much page overhead
+const FRAMES_PER_PAGE: usize = 5;
+
+/// Serves the pipeline as a continuous Ogg/Opus stream that Icecast clients understand,
+/// with the current track as ICY `StreamTitle` metadata. Every listener gets its own
+/// pipeline subscription.
+#[derive(Clone)]
+pub struct AudioStream {
+    pipeline: Pipeline,
+    name: String,
+    title: Arc<Mutex<String>>,
+}
+
+impl AudioStream {
+    pub fn new(pipeline: Pipeline, name: String) -> AudioStream {
+        AudioStream {
+            pipeline,
+            name,
+            title: Arc::new(Mutex::new(String::new())),
+        }
+    }
+
+    pub fn set_title(&self, title: String) {
+        *self.title.lock().unwrap() = title;
+    }
+
+    pub async fn serve(self, addr: String) {
+        let listener = TcpListener::bind(&addr).await.expect("Could not bind STREAM_ADDR");
+        println!("Serving audio stream on {}", addr);
+
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    println!("Could not accept stream connection: {}", e);
+                    continue;
+                }
+            };
+
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.accept(stream).await {
+                    println!("Stream listener dropped: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn accept(self, mut stream: TcpStream) -> io::Result<()> {
+        let request = http::read_request(&mut stream).await?;
+
+        if request.method != "GET" {
+            return http::respond(&mut stream, "405 Method Not Allowed", "text/plain", b"").await;
+        }
+
+        let metadata = request.header("Icy-MetaData") == Some("1");
+
+        let mut head = format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: audio/ogg\r\nCache-Control: no-cache\r\nicy-name: {}\r\n",
+            self.name
+        );
+        if metadata {
+            head.push_str(&format!("icy-metaint: {}\r\n", ICY_METAINT));
+        }
+        head.push_str("\r\n");
+
+        // Frames arrive on a blocking channel, so the listener gets its own thread
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+
+        let frames = self.pipeline.subscribe(FrameKind::Opus);
+        thread::spawn(move || {
+            let _ = self.write_stream(stream, head, frames, metadata);
+        });
+
+        Ok(())
+    }
+
+    fn write_stream(&self, mut stream: net::TcpStream, head: String, frames: FrameReader, metadata: bool) -> io::Result<()> {
+        stream.write_all(head.as_bytes())?;
+
+        let mut out = IcyWriter {
+            inner: stream,
+            title: self.title.clone(),
+            sent_title: None,
+            metadata,
+            until_meta: ICY_METAINT,
+        };
+
+        let serial = rand_serial();
+        let mut ogg = PacketWriter::new(Vec::new());
+        let comments = vec![format!("TITLE={}", self.name)];
+        ogg.write_packet(Cow::Owned(ogg_opus_head()), serial, PacketWriteEndInfo::EndPage, 0)?;
+        ogg.write_packet(Cow::Owned(ogg_opus_tags(&comments)), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        let mut granule = OPUS_PRE_SKIP as u64;
+        let mut frame_count = 0;
+
+        loop {
+            let packet = match frames.recv_timeout(Duration::from_secs(1)) {
+                Ok(frame) => match frame.opus {
+                    Some(packet) => packet,
+                    None => continue,
+                },
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+
+            granule += (FRAME_SAMPLES / 2) as u64;
+            frame_count += 1;
+
+            let end = if frame_count % FRAMES_PER_PAGE == 0 {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            ogg.write_packet(Cow::Owned(packet.to_vec()), serial, end, granule)?;
+
+            let pages = std::mem::take(ogg.inner_mut());
+            if !pages.is_empty() {
+                out.write_all(&pages)?;
+            }
+        }
+    }
+}
+
+fn rand_serial() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(1)
+}
+
+/// Interleaves ICY metadata blocks into the audio when the listener asked for them.
+struct IcyWriter<W: Write> {
+    inner: W,
+    title: Arc<Mutex<String>>,
+    sent_title: Option<String>,
+    metadata: bool,
+    until_meta: usize,
+}
+
+impl<W: Write> IcyWriter<W> {
+    fn write_metadata(&mut self) -> io::Result<()> {
+        let title = self.title.lock().unwrap().clone();
+
+        // Only repeat the title when it changed, an empty block is a single zero byte
+        if self.sent_title.as_ref() == Some(&title) {
+            return self.inner.write_all(&[0]);
+        }
+
+        let mut block = format!("StreamTitle='{}';", title.replace('\'', "")).into_bytes();
+        block.truncate(255 * 16);
+        let blocks = (block.len() + 15) / 16;
+        block.resize(blocks * 16, 0);
+
+        self.inner.write_all(&[blocks as u8])?;
+        self.inner.write_all(&block)?;
+        self.sent_title = Some(title);
+
+        Ok(())
+    }
+
+    fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if !self.metadata {
+            return self.inner.write_all(data);
+        }
+
+        while !data.is_empty() {
+            let n = data.len().min(self.until_meta);
+            self.inner.write_all(&data[..n])?;
+            data = &data[n..];
+            self.until_meta -= n;
+
+            if self.until_meta == 0 {
+                self.write_metadata()?;
+                self.until_meta = ICY_METAINT;
+            }
+        }
+
+        Ok(())
+    }
+}
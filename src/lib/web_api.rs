@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use librespot::core::keymaster;
+use librespot::core::session::Session;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::sleep;
+
+const API_URL: &str = "https://api.spotify.com/v1";
+
+// librespot's own client ID, keymaster hands out Web API tokens for it
+const KEYMASTER_CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+const KEYMASTER_SCOPES: &str = "user-read-playback-state,user-modify-playback-state";
+
+/// Where playback was before the session went away.
+#[derive(Clone, Debug)]
+pub struct RestorePoint {
+    pub track_uri: String,
+    pub position_ms: u32,
+    pub playing: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Device {
+    pub id: Option<String>,
+    pub name: String,
+    pub is_active: bool,
+}
+
+#[derive(Deserialize)]
+struct PlaybackState {
+    device: Option<Device>,
+}
+
+/// Gets a Web API access token through the session, so it works with cached credentials too.
+pub async fn token(session: &Session) -> Result<String, String> {
+    keymaster::get_token(session, KEYMASTER_CLIENT_ID, KEYMASTER_SCOPES)
+        .await
+        .map(|t| t.access_token)
+        .map_err(|e| format!("{:?}", e))
+}
+
+async fn put(token: &str, path: &str, body: serde_json::Value) -> Result<(), String> {
+    let response = reqwest::Client::new()
+        .put(&format!("{}{}", API_URL, path))
+        .bearer_auth(token)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("{} returned {}", path, response.status()));
+    }
+
+    Ok(())
+}
+
+/// The device Spotify currently plays on, if any.
+pub async fn active_device(session: &Session) -> Result<Option<Device>, String> {
+    let response = reqwest::Client::new()
+        .get(&format!("{}/me/player", API_URL))
+        .bearer_auth(token(session).await?)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // No content means nothing is playing anywhere
+    if response.status().as_u16() == 204 {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(format!("/me/player returned {}", response.status()));
+    }
+
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    let state: PlaybackState = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+
+    Ok(state.device.filter(|d| d.is_active))
+}
+
+/// Moves playback back onto this session's Connect device and seeks to where it was.
+/// The context and queue come back with it, since Spotify keeps those server side.
+pub async fn restore_playback(session: &Session, restore: &RestorePoint) -> Result<(), String> {
+    let token = token(session).await?;
+    let device_id = session.device_id().to_string();
+
+    // The new Spirc needs a moment before Spotify lists the device
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match put(&token, "/me/player", json!({ "device_ids": [device_id], "play": restore.playing })).await {
+            Ok(()) => break,
+            Err(e) if attempts >= 5 => return Err(e),
+            Err(_) => sleep(Duration::from_secs(2)).await,
+        }
+    }
+
+    put(
+        &token,
+        &format!("/me/player/seek?position_ms={}&device_id={}", restore.position_ms, device_id),
+        json!({}),
+    ).await
+}
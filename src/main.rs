@@ -1,5 +1,8 @@
 use std::{env, thread};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
 
 use async_ctrlc;
@@ -7,9 +10,9 @@ use async_ctrlc::CtrlC;
 use futures::sink::Buffer;
 use futures::StreamExt;
 use librespot::core::mercury::MercuryError;
+use librespot::core::session::Session;
 use librespot::playback::config::Bitrate;
 use librespot::playback::player::PlayerEvent;
-use serde::{Deserialize, Serialize};
 use songbird::{Call, ConnectionInfo, input};
 use songbird::id::{ChannelId, GuildId, UserId};
 use songbird::shards::Shard;
@@ -17,372 +20,899 @@ use tokio::sync::Mutex;
 use tokio::time::sleep;
 use tracing::log::{Level, log_enabled};
 
-use lib::player::SpotifyPlayer;
-
-use crate::groover::Groover;
+use lib::player::{bitrate_for_channel, ConnectSettings, DeviceLostPolicy, SpotifyPlayer};
+use lib::auth::OAuthMode;
+use lib::cache::CacheSettings;
+use lib::metadata::MetadataCache;
+use lib::offline::{OfflineIndex, OfflineMode};
+use lib::pipeline::{FrameKind, Pipeline};
+use lib::recorder::{Recorder, RecordingConfig};
+use lib::sink::{BackpressurePolicy, SinkEvent};
+use lib::stream::AudioStream;
+use lib::web_api::RestorePoint;
+
+use crate::groover::{DriverEvent, Groover};
+use crate::idle::IdleTimer;
+use crate::membership::Membership;
+use crate::operator::{GrooverEvent, Operator, OperatorMsg, PresenceSettings, PresenceUpdate, Status};
 
 mod groover;
+mod idle;
+mod membership;
+mod operator;
+#[cfg(feature = "standalone")]
+mod standalone;
 
 mod lib {
+    pub mod auth;
+    pub mod cache;
+    pub mod http;
+    pub mod metadata;
+    pub mod metrics;
+    pub mod offline;
+    pub mod pipeline;
     pub mod player;
+    pub mod recorder;
+    pub mod sink;
+    pub mod stream;
+    pub mod web_api;
 }
 
-/*pub struct UserIdKey;
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const SESSION_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+// Failed reconnects before falling back to playing from the cache
+const OFFLINE_AFTER_ATTEMPTS: u32 = 3;
+const DEVICE_LOST_CHECK_DELAY: Duration = Duration::from_secs(2);
+
+/// Leaves the call and stops being a Connect device, and has the gateway leave too.
+async fn leave_voice(player: &Mutex<SpotifyPlayer>, driver: &Mutex<Groover>, operator: &Operator) {
+    player.lock().await.disable_connect().await;
+    leave_call(player, driver, operator).await;
+}
 
-pub struct GuildIdKey;
+/// Leaves the call and has the gateway leave too, Groover stays in the Connect device list.
+async fn leave_call(player: &Mutex<SpotifyPlayer>, driver: &Mutex<Groover>, operator: &Operator) {
+    player.lock().await.emitted_sink.flush();
+    driver.lock().await.disconnect().await;
 
-impl TypeMapKey for UserIdKey {
-    type Value = id::UserId;
+    operator.publish(GrooverEvent::LeaveRequested {}).await;
 }
 
-impl TypeMapKey for GuildIdKey {
-    type Value = id::GuildId;
+/// Leaves the call after playback has been idle for too long.
+async fn leave_idle(player: Arc<Mutex<SpotifyPlayer>>, driver: Arc<Mutex<Groover>>, operator: Operator, idle: Duration) {
+    if !driver.lock().await.is_connected() {
+        return;
+    }
+
+    println!("Idle for {}s, leaving", idle.as_secs());
+    operator.publish(GrooverEvent::LeftIdle { idle_secs: idle.as_secs() }).await;
+    leave_voice(&player, &driver, &operator).await;
 }
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn ready(&self, ctx: Context, ready: Ready) {
-        println!("Ready!");
-        println!("Invite me with https://discord.com/api/oauth2/authorize?client_id={}&permissions=36700160&scope=bot", ready.user.id);
+/// Checks whether playback stopped because it moved to another Connect device, and
+/// handles that according to `policy`.
+async fn check_device_lost(
+    player: Arc<Mutex<SpotifyPlayer>>,
+    driver: Arc<Mutex<Groover>>,
+    operator: Operator,
+    policy: DeviceLostPolicy,
+) {
+    // Spotify takes a moment to report the new device as active
+    sleep(DEVICE_LOST_CHECK_DELAY).await;
+
+    let session = {
+        let player = player.lock().await;
+        match (player.session.clone(), player.spirc.is_some()) {
+            (Some(session), true) => session,
+            _ => return,
+        }
+    };
+    if !driver.lock().await.is_connected() {
+        return;
+    }
 
-        ctx.set_presence(None, user::OnlineStatus::Online).await;
+    let device = match lib::web_api::active_device(&session).await {
+        Ok(Some(device)) if device.id.as_deref() != Some(session.device_id()) => device,
+        Ok(_) => return,
+        Err(e) => {
+            println!("Could not check the active Spotify device: {}", e);
+            return;
+        }
+    };
+
+    println!("Playback moved to {}", device.name);
+    if policy != DeviceLostPolicy::Silent {
+        operator.publish(GrooverEvent::DeviceLost {
+            device_name: device.name,
+            left: policy == DeviceLostPolicy::Leave,
+        }).await;
     }
+    // Keep Connect running so playback can be moved back to us
+    if policy == DeviceLostPolicy::Leave {
+        leave_call(&player, &driver, &operator).await;
+    }
+}
 
-    async fn cache_ready(&self, ctx: Context, guilds: Vec<id::GuildId>) {
-        let guild_id = match guilds.first() {
-            Some(guild_id) => *guild_id,
-            None => {
-                panic!("Not currently in any guilds.");
-            }
-        };
-        let data = ctx.data.read().await;
-
-        let player = data.get::<SpotifyPlayerKey>().unwrap().clone();
-        let user_id = *data
-            .get::<UserIdKey>()
-            .expect("User ID placed in at initialisation.");
-
-        // Handle case when user is in VC when bot starts
-        let guild = ctx
-            .cache
-            .guild(guild_id)
-            .await
-            .expect("Could not find guild in cache.");
-
-        let channel_id = guild
-            .voice_states
-            .get(&user_id)
-            .and_then(|voice_state| voice_state.channel_id);
-        drop(guild);
-
-        if channel_id.is_some() {
-            // Enable casting
-            player.lock().await.enable_connect().await;
+/// Moves playback back onto the rebuilt Connect device in the background.
+fn spawn_restore(session: Option<Session>, restore: Option<RestorePoint>) {
+    let (session, restore) = match (session, restore) {
+        (Some(session), Some(restore)) => (session, restore),
+        _ => return,
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = lib::web_api::restore_playback(&session, &restore).await {
+            println!("Could not restore playback after rebuilding Connect: {}", e);
         }
+    });
+}
 
-        let c = ctx.clone();
+#[tokio::main]
+async fn main() {
+    let guild_id =
+        env::var("DISCORD_GUILD_ID").expect("Expected a Discord guild ID in the environment");
 
-        // Handle Spotify events
-        tokio::spawn(async move {
-            loop {
-                let channel = player.lock().await.event_channel.clone().unwrap();
-                let mut receiver = channel.lock().await;
+    let user_id =
+        env::var("DISCORD_USER_ID").expect("Expected a Discord user ID in the environment");
 
-                let event = match receiver.recv().await {
-                    Some(e) => e,
-                    None => {
-                        // Busy waiting bad but quick and easy
-                        sleep(Duration::from_millis(256)).await;
-                        continue;
-                    }
-                };
+    let cache_settings = CacheSettings::from_env();
 
-                match event {
-                    PlayerEvent::Stopped { .. } => {
-                        c.set_presence(None, user::OnlineStatus::Online).await;
+    let backpressure = match env::var("SINK_BACKPRESSURE") {
+        Ok(policy) => policy.parse().expect("Invalid SINK_BACKPRESSURE"),
+        Err(_) => BackpressurePolicy::Drop,
+    };
 
-                        let manager = songbird::get(&c)
-                            .await
-                            .expect("Songbird Voice client placed in at initialisation.")
-                            .clone();
+    // The user whose voice channel Groover follows, if any
+    let follow_user: Option<u64> = env::var("FOLLOW_USER_ID")
+        .ok()
+        .map(|id| id.parse().expect("Invalid FOLLOW_USER_ID"));
+    let mut followed_channel: Option<u64> = None;
 
-                        let _ = manager.remove(guild_id).await;
-                    }
+    let bot_id: u64 = user_id.parse().expect("Invalid DISCORD_USER_ID");
+    let mut membership = Membership::new(bot_id);
+    let mut channel_empty = false;
+    let membership_generation = Arc::new(AtomicU64::new(0));
+    let paused_for_empty = Arc::new(AtomicBool::new(false));
 
-                    PlayerEvent::Started { .. } => {
-                        let manager = songbird::get(&c)
-                            .await
-                            .expect("Songbird Voice client placed in at initialisation.")
-                            .clone();
-
-                        let guild = c
-                            .cache
-                            .guild(guild_id)
-                            .await
-                            .expect("Could not find guild in cache.");
-
-                        let channel_id = match guild
-                            .voice_states
-                            .get(&user_id)
-                            .and_then(|voice_state| voice_state.channel_id)
-                        {
-                            Some(channel_id) => channel_id,
-                            None => {
-                                println!("Could not find user in VC.");
-                                println!("{}", &user_id);
-                                println!("{}", &guild_id);
-                                continue;
-                            }
-                        };
-                        let _handler = manager.join(guild_id, channel_id).await;
+    let empty_pause_grace = Duration::from_secs(match env::var("EMPTY_PAUSE_GRACE_SECS") {
+        Ok(secs) => secs.parse().expect("Invalid EMPTY_PAUSE_GRACE_SECS"),
+        Err(_) => 30,
+    });
+    // Resuming is opt-in, the grace period keeps someone passing through from restarting it
+    let auto_resume_grace = match env::var("AUTO_RESUME_GRACE_SECS") {
+        Ok(secs) => Some(Duration::from_secs(secs.parse().expect("Invalid AUTO_RESUME_GRACE_SECS"))),
+        Err(_) => None,
+    };
 
-                        if let Some(handler_lock) = manager.get(guild_id) {
-                            let mut handler = handler_lock.lock().await;
+    let operator = Operator::connect(guild_id.clone()).await;
 
-                            let mut decoder = input::codec::OpusDecoderState::new().unwrap();
-                            decoder.allow_passthrough = false;
+    let oauth_mode = match env::var("OAUTH_MODE").as_deref() {
+        Ok("callback") => OAuthMode::Callback { listen_addr: env::var("OAUTH_LISTEN_ADDR").ok() },
+        Ok("operator") => OAuthMode::Operator(operator.clone()),
+        _ => OAuthMode::Stdin,
+    };
 
-                            let source = input::Input::new(
-                                true,
-                                input::reader::Reader::Extension(Box::new(
-                                    player.lock().await.emitted_sink.clone(),
-                                )),
-                                input::codec::Codec::FloatPcm,
-                                input::Container::Raw,
-                                None,
-                            );
+    let bitrate = match env::var("BITRATE") {
+        Ok(bitrate) => Bitrate::from_str(&bitrate).expect("Invalid BITRATE, expected 96, 160 or 320"),
+        Err(_) => Bitrate::Bitrate320,
+    };
 
-                            handler.set_bitrate(songbird::Bitrate::Auto);
+    let connect_settings = ConnectSettings::from_env(guild_id.clone());
 
-                            handler.play_source(source);
-                        } else {
-                            println!("Could not fetch guild by ID.");
-                        }
-                    }
+    // Start offline instead of failing when Spotify is unreachable but we have cached tracks
+    let offline_index = OfflineIndex::load(cache_settings.audio_dir.clone());
+    let offline_fallback = cache_settings.audio_dir.is_some() && !offline_index.is_empty();
 
-                    PlayerEvent::Paused { .. } => {
-                        c.set_presence(None, user::OnlineStatus::Online).await;
-                    }
+    let player = Arc::new(Mutex::new(
+        SpotifyPlayer::new(bitrate, cache_settings, backpressure, oauth_mode, connect_settings, offline_fallback).await
+    ));
 
-                    PlayerEvent::Playing { track_id, .. } => {
-                        let track: Result<librespot::metadata::Track, MercuryError> =
-                            librespot::metadata::Metadata::get(
-                                &player.lock().await.session,
-                                track_id,
-                            )
-                                .await;
-
-                        if let Ok(track) = track {
-                            let artist: Result<librespot::metadata::Artist, MercuryError> =
-                                librespot::metadata::Metadata::get(
-                                    &player.lock().await.session,
-                                    *track.artists.first().unwrap(),
-                                )
-                                    .await;
-
-                            if let Ok(artist) = artist {
-                                let listening_to = format!("{}: {}", artist.name, track.name);
-
-                                c.set_presence(
-                                    Some(gateway::Activity::listening(listening_to)),
-                                    user::OnlineStatus::Online,
-                                )
-                                    .await;
-                            }
-                        }
-                    }
-                    PlayerEvent::VolumeSet { volume } => {
-                        let data2 = c.data.read().await;
-                        let player = data2.get::<SpotifyPlayerKey>().unwrap();
-                        // player.lock().await.spirc.as_ref().expect("").pause()
+    // Keep the access token fresh for when the session has to log in again
+    let player_clone = player.clone();
+    tokio::spawn(async move {
+        loop {
+            let tokens = player_clone.lock().await.tokens.clone();
+
+            let wait = match tokens.as_ref().and_then(|t| t.refresh_in()) {
+                Some(wait) if tokens.as_ref().unwrap().can_refresh() => wait.min(SESSION_CHECK_INTERVAL),
+                _ => SESSION_CHECK_INTERVAL,
+            };
+            sleep(wait).await;
+
+            if let Some(tokens) = tokens.as_ref() {
+                if tokens.can_refresh() && tokens.refresh_in().map_or(false, |d| d.as_secs() == 0) {
+                    if let Err(e) = tokens.refresh().await {
+                        println!("Could not refresh Spotify token: {}", e);
+                        sleep(SESSION_CHECK_INTERVAL).await;
+                        continue;
                     }
-                    _ => {}
                 }
             }
-        });
-    }
+        }
+    });
 
-    async fn voice_state_update(
-        &self,
-        ctx: Context,
-        _: Option<id::GuildId>,
-        old: Option<VoiceState>,
-        new: VoiceState,
-    ) {
-        let data = ctx.data.read().await;
+    let pipeline = Pipeline::start(player.lock().await.emitted_sink.clone());
 
-        let user_id = data.get::<UserIdKey>();
+    // Encode to Opus once in Groover instead of in songbird for every call
+    let input_kind = match env::var("OPUS_PIPELINE") {
+        Ok(v) if v == "0" || v == "false" => FrameKind::Pcm,
+        _ => FrameKind::Opus,
+    };
 
-        if new.user_id.to_string() != user_id.unwrap().to_string() {
-            return;
+    let audio_stream = match env::var("STREAM_ADDR") {
+        Ok(addr) => {
+            let name = env::var("STREAM_NAME").unwrap_or_else(|_| "Groover".into());
+            let stream = AudioStream::new(pipeline.clone(), name);
+            tokio::spawn(stream.clone().serve(addr));
+            Some(stream)
         }
+        Err(_) => None,
+    };
 
-        let player = data.get::<SpotifyPlayerKey>().unwrap();
+    let mut driver =  Arc::new(Mutex::new(Groover::new(guild_id.clone(), user_id.clone())));
 
-        let guild = ctx
-            .cache
-            .guild(ctx.cache.guilds().await.first().unwrap())
-            .await
-            .unwrap();
+    // Extra calls attached for broadcasting, keyed by guild and bot user. They are not
+    // rejoined when their connection drops, the operator attaches them again.
+    let mut targets: HashMap<(u64, u64), Groover> = HashMap::new();
 
-        // If user just connected
-        if old.clone().is_none() {
-            // Enable casting
-            player.lock().await.enable_connect().await;
-            return;
-        }
+    let mut commands = operator.commands().await;
 
-        // If user disconnected
-        if old.clone().unwrap().channel_id.is_some() && new.channel_id.is_none() {
-            // Disable casting
-            player.lock().await.disable_connect().await;
+    if let Ok(addr) = env::var("METRICS_ADDR") {
+        tokio::spawn(lib::metrics::serve(addr, player.lock().await.emitted_sink.clone(), pipeline.clone()));
+    }
 
-            // Disconnect
-            let manager = songbird::get(&ctx)
-                .await
-                .expect("Songbird Voice client placed in at initialisation.")
-                .clone();
+    let offline = Arc::new(Mutex::new(OfflineMode::default()));
+    if player.lock().await.session.is_none() {
+        offline.lock().await.active = true;
+        operator.publish(GrooverEvent::OfflineMode { active: true }).await;
+    }
 
-            let _handler = manager.remove(guild.id).await;
+    // Watch the session, and when the access point drops it, or was never reached, log in
+    // again with backoff and move playback back to where it was. Cached tracks can be
+    // played meanwhile.
+    let player_clone = player.clone();
+    let operator_clone = operator.clone();
+    let offline_clone = offline.clone();
+    tokio::spawn(async move {
+        loop {
+            sleep(SESSION_HEALTH_INTERVAL).await;
 
-            return;
-        }
+            if player_clone.lock().await.session.as_ref().map_or(false, |s| !s.is_invalid()) {
+                continue;
+            }
+
+            println!("Spotify session was lost, reconnecting");
+            operator_clone.publish(GrooverEvent::SessionLost {}).await;
 
-        // If user moved channels
-        if old.unwrap().channel_id.unwrap() != new.channel_id.unwrap() {
-            let bot_id = ctx.cache.current_user_id().await;
+            let restore = player_clone.lock().await.restore_point();
+            let mut backoff = RECONNECT_MIN_BACKOFF;
+            let mut attempts = 0;
+
+            loop {
+                attempts += 1;
+                match SpotifyPlayer::reauthenticate(&player_clone).await {
+                    Ok(()) => break,
+                    Err(e) => println!("Reconnect attempt {} failed: {}", attempts, e),
+                }
 
-            let bot_channel = guild
-                .voice_states
-                .get(&bot_id)
-                .and_then(|voice_state| voice_state.channel_id);
+                if attempts == OFFLINE_AFTER_ATTEMPTS && !offline_clone.lock().await.active {
+                    println!("Spotify is unreachable, switching to offline mode");
+                    offline_clone.lock().await.active = true;
+                    operator_clone.publish(GrooverEvent::OfflineMode { active: true }).await;
+                }
 
-            if Option::is_some(&bot_channel) {
-                let manager = songbird::get(&ctx)
-                    .await
-                    .expect("Songbird Voice client placed in at initialisation.")
-                    .clone();
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+            }
 
-                if let Some(guild_id) = ctx.cache.guilds().await.first() {
-                    let _handler = manager.join(*guild_id, new.channel_id.unwrap()).await;
+            {
+                let mut offline = offline_clone.lock().await;
+                if offline.active {
+                    offline.stop();
+                    offline.active = false;
+                    player_clone.lock().await.emitted_sink.flush();
+                    operator_clone.publish(GrooverEvent::OfflineMode { active: false }).await;
                 }
             }
 
-            return;
+            let mut error = None;
+            let session = player_clone.lock().await.session.clone();
+            if let (Some(restore), Some(session)) = (restore.as_ref(), session) {
+                if let Err(e) = lib::web_api::restore_playback(&session, restore).await {
+                    println!("Could not restore playback: {}", e);
+                    error = Some(e);
+                }
+            }
+
+            operator_clone.publish(GrooverEvent::SessionRestored {
+                attempts,
+                track_uri: restore.as_ref().map(|r| r.track_uri.clone()),
+                position_ms: restore.as_ref().map(|r| r.position_ms),
+                error,
+            }).await;
         }
-    }
-}*/
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "UserId")]
-struct UserIdDef(pub u64);
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "GuildId")]
-pub struct GuildIdDef(pub u64);
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "ConnectionInfo")]
-struct ConnectionInfoDef {
-    endpoint: String,
-    #[serde(with = "GuildIdDef")]
-    guild_id: GuildId,
-    session_id: String,
-    token: String,
-    #[serde(with = "UserIdDef")]
-    user_id: UserId,
-}
+    });
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type", content = "value")]
-pub enum OperatorMsg {
-    Join {
-        #[serde(with = "ConnectionInfoDef")]
-        info: ConnectionInfo
-    },
-    PausePlay {
-    },
-}
+    let recording_dir = env::var("RECORDING_DIR").unwrap_or_else(|_| "recordings".into());
+    let recorder: Arc<Mutex<Option<Recorder>>> = Arc::new(Mutex::new(None));
+    let (recording_done, mut recording_done_rx) = tokio::sync::mpsc::unbounded_channel();
 
-#[tokio::main]
-async fn main() {
-    let guild_id =
-        env::var("DISCORD_GUILD_ID").expect("Expected a Discord guild ID in the environment");
+    let recorder_clone = recorder.clone();
+    let operator_clone = operator.clone();
+    tokio::spawn(async move {
+        while let Some(summary) = recording_done_rx.recv().await {
+            let mut recorder = recorder_clone.lock().await;
+            if recorder.as_ref().map_or(false, |r| r.is_finished()) {
+                *recorder = None;
+            }
+            drop(recorder);
 
-    let user_id =
-        env::var("DISCORD_USER_ID").expect("Expected a Discord user ID in the environment");
+            operator_clone.publish(GrooverEvent::RecordingStopped {
+                files: summary.files.iter().map(|f| f.display().to_string()).collect(),
+                error: summary.error.map(|e| e.to_string()),
+            }).await;
+        }
+    });
 
-    let mut cache_dir = None;
+    // Publish what the voice driver does, and rejoin with backoff when it loses the call
+    let mut driver_events = driver.lock().await.watch_events();
+    let driver_clone = driver.clone();
+    let pipeline_clone = pipeline.clone();
+    let operator_clone = operator.clone();
+    tokio::spawn(async move {
+        let mut backoff = RECONNECT_MIN_BACKOFF;
+        let mut attempts = 0;
 
-    if let Ok(c) = env::var("CACHE_DIR") {
-        cache_dir = Some(c);
-    }
+        while let Some(event) = driver_events.recv().await {
+            match event {
+                DriverEvent::Connected => {
+                    let rejoin_attempts = if attempts > 0 {
+                        // A fresh connection starts without the input, attach it again
+                        driver_clone.lock().await.set_source(pipeline_clone.subscribe(input_kind).input());
+                        Some(attempts)
+                    } else {
+                        None
+                    };
+                    backoff = RECONNECT_MIN_BACKOFF;
+                    attempts = 0;
+
+                    operator_clone.publish(GrooverEvent::VoiceConnected { rejoin_attempts }).await;
+                    continue;
+                }
+                DriverEvent::Reconnected => {
+                    operator_clone.publish(GrooverEvent::VoiceReconnected {}).await;
+                    continue;
+                }
+                DriverEvent::ConnectFailed => operator_clone.publish(GrooverEvent::VoiceConnectFailed {}).await,
+                DriverEvent::ReconnectFailed => operator_clone.publish(GrooverEvent::VoiceReconnectFailed {}).await,
+            }
 
-    let player = Arc::new(Mutex::new(
-        SpotifyPlayer::new(Bitrate::Bitrate320, cache_dir).await
-    ));
+            let generation = {
+                let driver = driver_clone.lock().await;
+                if !driver.should_rejoin() {
+                    continue;
+                }
+                driver.generation()
+            };
 
-    let nats_url = env::var("NATS_URL").expect("Expected a NATS URL in the environment");
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
 
-    let mut nc = async_nats::connect(nats_url).await.unwrap();
+            // A join or leave during the backoff replaced the lost connection
+            let mut driver = driver_clone.lock().await;
+            if !driver.should_rejoin() || driver.generation() != generation {
+                continue;
+            }
 
-    let mut driver =  Arc::new(Mutex::new(Groover::new(guild_id.clone(), user_id.clone())));
+            attempts += 1;
+            println!("Lost the voice connection, rejoining (attempt {})", attempts);
+            driver.rejoin().await;
+        }
+    });
+
+    let idle = IdleTimer::from_env();
 
-    let mut sub = nc.subscribe(guild_id.clone()).await.unwrap();
+    let device_lost_policy = match env::var("DEVICE_LOST_POLICY") {
+        Ok(policy) => policy.parse().expect("Invalid DEVICE_LOST_POLICY"),
+        Err(_) => DeviceLostPolicy::Leave,
+    };
 
     let player_clone = player.clone();
-    let mut driver_clone = driver.clone();
+    let audio_stream_clone = audio_stream.clone();
+    let offline_index_clone = offline_index.clone();
+    let operator_clone = operator.clone();
+    let driver_clone = driver.clone();
+    let idle_clone = idle.clone();
     tokio::spawn(async move {
-        loop {
-            let channel = player_clone.lock().await.event_channel.clone().unwrap();
-            let mut receiver = channel.lock().await;
-
-            let event = match receiver.recv().await {
-                Some(e) => e,
-                None => {
-                    // Busy waiting bad but quick and easy
-                    sleep(Duration::from_millis(256)).await;
-                    continue;
+        let mut is_playing = false;
+        let mut current_track = None;
+        let metadata = MetadataCache::new();
+        let presence = PresenceSettings::from_env();
+        // Bumped whenever what we show changes, lookups that finish late are dropped
+        let shown_generation = Arc::new(AtomicU64::new(0));
+
+        let player_events = player_clone.lock().await.player_events.clone();
+        let mut receiver = player_events.lock().await;
+
+        while let Some(event) = receiver.recv().await {
+            player_clone.lock().await.track_playback(&event);
+            let was_playing = is_playing;
+
+            match event {
+                PlayerEvent::Paused { .. } | PlayerEvent::Stopped { .. } => {
+                    if let Some(timeout) = idle_clone.timeout() {
+                        idle_clone.start(leave_idle(player_clone.clone(), driver_clone.clone(), operator_clone.clone(), timeout));
+                    }
                 }
-            };
+                PlayerEvent::Loading { .. } | PlayerEvent::Playing { .. } => idle_clone.cancel(),
+                _ => {}
+            }
+
+            // Stopped is also what we get when someone moves playback to their phone
+            if let PlayerEvent::Stopped { .. } = event {
+                tokio::spawn(check_device_lost(
+                    player_clone.clone(),
+                    driver_clone.clone(),
+                    operator_clone.clone(),
+                    device_lost_policy,
+                ));
+            }
+
+            match event {
+                PlayerEvent::Loading { .. }
+                | PlayerEvent::Stopped { .. }
+                | PlayerEvent::Paused { .. } => is_playing = false,
+                PlayerEvent::Playing { .. } => is_playing = true,
+                _ => {}
+            }
+
+            // Keep the audio key of everything we play, for offline mode
+            if let PlayerEvent::Playing { track_id, .. } = event {
+                let (session, bitrate) = {
+                    let player = player_clone.lock().await;
+                    (player.session.clone(), player.bitrate())
+                };
+                if let (false, Some(session)) = (offline_index_clone.contains(&track_id.to_uri()), session) {
+                    let index = offline_index_clone.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = lib::cache::index_track(&session, &index, track_id, bitrate).await {
+                            println!("Could not index {} for offline playback: {}", track_id.to_uri(), e);
+                        }
+                    });
+                }
+            }
 
+            // Playing also comes after every seek, only announce new tracks and resumes
             match event {
-                PlayerEvent::Started { .. } => {
-                    // funny stuff happens if the source is set multiple times
-                    if driver_clone.lock().await.is_source_set {
-                       return;
+                PlayerEvent::Playing { track_id, .. } if current_track != Some(track_id) || !was_playing => {
+                    let new_track = current_track != Some(track_id);
+                    current_track = Some(track_id);
+
+                    let session = match player_clone.lock().await.session.clone() {
+                        Some(session) => session,
+                        None => continue,
+                    };
+                    let generation = shown_generation.fetch_add(1, Ordering::SeqCst) + 1;
+                    let shown_generation = shown_generation.clone();
+                    let metadata = metadata.clone();
+                    let operator = operator_clone.clone();
+                    let stream = audio_stream_clone.clone();
+                    let presence = presence.clone();
+                    tokio::spawn(async move {
+                        let info = match metadata.track(&session, track_id).await {
+                            Ok(info) => info,
+                            Err(e) => {
+                                println!("Could not look up {}: {}", track_id.to_uri(), e);
+                                return;
+                            }
+                        };
+
+                        // Still playing this track, nothing paused or stopped it meanwhile
+                        if shown_generation.load(Ordering::SeqCst) == generation {
+                            operator.publish_presence(presence.update_for(&info)).await;
+                            if let (true, Some(stream)) = (new_track, stream.as_ref()) {
+                                stream.set_title(info.display_title());
+                            }
+                        }
+                        if new_track {
+                            operator.publish(GrooverEvent::NowPlaying(info)).await;
+                        }
+                    });
+                }
+                PlayerEvent::Paused { .. } => {
+                    shown_generation.fetch_add(1, Ordering::SeqCst);
+                    if was_playing {
+                        operator_clone.publish_presence(PresenceUpdate::Clear {}).await;
+                    }
+                }
+                PlayerEvent::Stopped { .. } => {
+                    shown_generation.fetch_add(1, Ordering::SeqCst);
+                    current_track = None;
+                    if let Some(stream) = audio_stream_clone.as_ref() {
+                        stream.set_title(String::new());
                     }
-                    let mut decoder = input::codec::OpusDecoderState::new().unwrap();
-                    decoder.allow_passthrough = false;
-                    let source = input::Input::new(
-                        true,
-                        input::reader::Reader::Extension(Box::new(
-                            player_clone.lock().await.emitted_sink.clone(),
-                        )),
-                        input::codec::Codec::FloatPcm,
-                        input::Container::Raw,
-                        None,
-                    );
-                    driver_clone.lock().await.set_source(source);
+                    operator_clone.publish_presence(PresenceUpdate::Clear {}).await;
                 }
                 _ => {}
             }
         }
     });
 
-    nc.publish("ready".into(), guild_id.into()).await;
+    // Pause through Spotify Connect while nobody is listening, see SINK_BACKPRESSURE
+    let player_clone = player.clone();
+    tokio::spawn(async move {
+        let sink_events = player_clone.lock().await.sink_events.clone();
+        let mut receiver = sink_events.lock().await;
+        let mut paused_by_sink = false;
+
+        while let Some(event) = receiver.recv().await {
+            let player = player_clone.lock().await;
+            let spirc = match player.spirc.as_ref() {
+                Some(spirc) => spirc,
+                None => continue,
+            };
+
+            match event {
+                SinkEvent::ConsumerLost => {
+                    spirc.pause();
+                    paused_by_sink = true;
+                }
+                SinkEvent::ConsumerReturned => {
+                    // Only resume what we paused ourselves
+                    if paused_by_sink {
+                        spirc.play();
+                        paused_by_sink = false;
+                    }
+                }
+            }
+        }
+    });
+
+    #[cfg(feature = "standalone")]
+    tokio::spawn(standalone::run(operator.clone(), guild_id.clone()));
+
+    operator.announce_ready().await;
+
+    let ctrlc = CtrlC::new().expect("Could not create Ctrl+C handler");
+    tokio::pin!(ctrlc);
 
     loop {
-        while let Some(msg) = sub.next().await {
-            let omsg: OperatorMsg = serde_json::from_slice(&msg.payload).unwrap();
-            match omsg {
-                OperatorMsg::PausePlay{} => {
-                    player.lock().await.spirc.as_ref().unwrap().play_pause();
+        let command = tokio::select! {
+            Some(command) = commands.next() => command,
+            _ = &mut ctrlc => break,
+        };
+
+        // Any command counts as activity and restarts a running idle timer, except the
+        // read-only ones monitoring sends all the time and forwarded gateway voice states
+        let activity = !matches!(
+            command.msg,
+            OperatorMsg::Status {} | OperatorMsg::CacheUsage {} | OperatorMsg::VoiceStateUpdate { .. }
+        );
+        if let (true, true, Some(timeout)) = (idle.is_armed(), activity, idle.timeout()) {
+            idle.start(leave_idle(player.clone(), driver.clone(), operator.clone(), timeout));
+        }
+
+        match command.msg {
+            OperatorMsg::PausePlay{} => {
+                match player.lock().await.spirc.as_ref() {
+                    Some(spirc) => spirc.play_pause(),
+                    None => println!("Spotify Connect is not running, ignoring PausePlay"),
+                }
+            }
+            OperatorMsg::Join { info, bitrate } => {
+                let mut driver = driver.lock().await;
+                let mut player = player.lock().await;
+
+                if let Some(bitrate) = bitrate {
+                    driver.set_bitrate(songbird::Bitrate::BitsPerSecond(bitrate as i32));
+                    pipeline.set_opus_bitrate(Some(bitrate as i32));
+
+                    let quality = bitrate_for_channel(bitrate);
+                    if player.bitrate() != quality {
+                        let restore = player.spirc.as_ref().and(player.restore_point());
+                        player.set_bitrate(quality).await;
+                        spawn_restore(player.session.clone(), restore);
+                    }
+                }
+
+                driver.connect(info).await;
+
+                // Reads never block, so the source can stay set while nothing plays
+                if !driver.is_source_set {
+                    driver.set_source(pipeline.subscribe(input_kind).input());
                 }
-                OperatorMsg::Join { info } => {
-                    driver.lock().await.connect(info).await;
-                    player.lock().await.enable_connect().await;
+
+                if player.spirc.is_none() {
+                    player.enable_connect().await;
                 }
             }
+            OperatorMsg::Leave {} => {
+                player.lock().await.disable_connect().await;
+                player.lock().await.emitted_sink.flush();
+                driver.lock().await.disconnect().await;
+            }
+            OperatorMsg::VoiceStateUpdate { user_id, channel_id, bot } => {
+                membership.update(user_id, channel_id, bot);
+
+                // Kicked or disconnected through Discord, rejoining would only fight it
+                let in_call = {
+                    let driver = driver.lock().await;
+                    driver.is_connected() || driver.should_rejoin()
+                };
+                if user_id == bot_id && channel_id.is_none() && in_call {
+                    println!("Disconnected from the voice channel");
+                    player.lock().await.emitted_sink.flush();
+                    driver.lock().await.disconnect().await;
+                }
+
+                // Pause once the channel stayed empty for a while, resume when someone
+                // stays back long enough, later updates cancel pending ones
+                match membership.listeners() {
+                    Some(0) if !channel_empty => {
+                        channel_empty = true;
+                        let generation = membership_generation.fetch_add(1, Ordering::SeqCst) + 1;
+                        let player = player.clone();
+                        let membership_generation = membership_generation.clone();
+                        let paused_for_empty = paused_for_empty.clone();
+                        tokio::spawn(async move {
+                            sleep(empty_pause_grace).await;
+                            if membership_generation.load(Ordering::SeqCst) != generation {
+                                return;
+                            }
+
+                            let player = player.lock().await;
+                            let playing = player.restore_point().map_or(false, |r| r.playing);
+                            if let (Some(spirc), true) = (player.spirc.as_ref(), playing) {
+                                println!("Nobody is listening, pausing");
+                                spirc.pause();
+                                paused_for_empty.store(true, Ordering::SeqCst);
+                            }
+                        });
+                    }
+                    Some(listeners) if listeners > 0 && channel_empty => {
+                        channel_empty = false;
+                        let generation = membership_generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+                        if let Some(resume_grace) = auto_resume_grace {
+                            let player = player.clone();
+                            let membership_generation = membership_generation.clone();
+                            let paused_for_empty = paused_for_empty.clone();
+                            tokio::spawn(async move {
+                                sleep(resume_grace).await;
+                                if membership_generation.load(Ordering::SeqCst) != generation {
+                                    return;
+                                }
+
+                                // Only resume what we paused ourselves
+                                if paused_for_empty.swap(false, Ordering::SeqCst) {
+                                    if let Some(spirc) = player.lock().await.spirc.as_ref() {
+                                        println!("Listeners are back, resuming");
+                                        spirc.play();
+                                    }
+                                }
+                            });
+                        } else {
+                            paused_for_empty.store(false, Ordering::SeqCst);
+                        }
+                    }
+                    // Not in a channel, so nothing scheduled for the old one applies anymore
+                    None => {
+                        channel_empty = false;
+                        membership_generation.fetch_add(1, Ordering::SeqCst);
+                        paused_for_empty.store(false, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+
+                if follow_user != Some(user_id) {
+                    continue;
+                }
+
+                let previous = std::mem::replace(&mut followed_channel, channel_id);
+                match (previous, channel_id) {
+                    // User disconnected
+                    (Some(_), None) => leave_voice(&player, &driver, &operator).await,
+                    // User connected or moved channels
+                    (previous, Some(channel_id)) if previous != Some(channel_id) => {
+                        if previous.is_none() {
+                            let mut player = player.lock().await;
+                            if player.spirc.is_none() {
+                                player.enable_connect().await;
+                            }
+                        }
+                        operator.publish(GrooverEvent::JoinRequested { channel_id }).await;
+                    }
+                    _ => {}
+                }
+            }
+            OperatorMsg::StartRecording { format, max_duration_secs, rotate_secs } => {
+                let mut recorder = recorder.lock().await;
+                if recorder.is_some() {
+                    println!("Already recording");
+                    continue;
+                }
+
+                let config = RecordingConfig {
+                    format,
+                    directory: recording_dir.clone().into(),
+                    max_duration: max_duration_secs.map(Duration::from_secs),
+                    rotate_after: rotate_secs.map(Duration::from_secs),
+                };
+
+                match Recorder::start(&pipeline, config, recording_done.clone()) {
+                    Ok(r) => {
+                        *recorder = Some(r);
+                        operator.publish(GrooverEvent::RecordingStarted {
+                            format: format!("{:?}", format).to_lowercase(),
+                            directory: recording_dir.clone(),
+                        }).await;
+                    }
+                    Err(e) => println!("Could not start recording: {}", e),
+                }
+            }
+            OperatorMsg::Attach { info } => {
+                let key = (info.guild_id.0, info.user_id.0);
+                if let Some(mut target) = targets.remove(&key) {
+                    target.disconnect().await;
+                }
+
+                let mut target = Groover::with_ids(info.guild_id, info.user_id);
+                target.connect(info).await;
+                target.set_source(pipeline.subscribe(input_kind).input());
+                targets.insert(key, target);
+
+                operator.publish(GrooverEvent::TargetAttached { guild_id: key.0, user_id: key.1 }).await;
+            }
+            OperatorMsg::Detach { guild_id, user_id } => {
+                // Dropping the call drops its input, which unsubscribes it from the pipeline
+                if let Some(mut target) = targets.remove(&(guild_id, user_id)) {
+                    target.disconnect().await;
+                    operator.publish(GrooverEvent::TargetDetached { guild_id, user_id }).await;
+                }
+            }
+            OperatorMsg::StopRecording {} => {
+                if let Some(recorder) = recorder.lock().await.as_ref() {
+                    recorder.stop();
+                }
+            }
+            OperatorMsg::ForgetCredentials {} => {
+                match player.lock().await.forget_credentials() {
+                    Ok(()) => println!("Forgot stored Spotify credentials"),
+                    Err(e) => println!("Could not forget stored Spotify credentials: {}", e),
+                }
+            }
+            OperatorMsg::ConfigureConnect { name, guild_name, device_type, volume, volume_ctrl, autoplay } => {
+                let mut player = player.lock().await;
+
+                let mut settings = player.connect_settings.clone();
+                if let Err(e) = settings.update(name, device_type, volume, volume_ctrl, autoplay) {
+                    println!("Could not configure Spotify Connect: {}", e);
+                    continue;
+                }
+                if guild_name.is_some() {
+                    settings.guild_name = guild_name;
+                }
+
+                // Restarting the device stops playback, move it back once the new one is up
+                let restore = player.spirc.as_ref().and(player.restore_point());
+                player.set_connect_settings(settings).await;
+                spawn_restore(player.session.clone(), restore);
+            }
+            OperatorMsg::SetBitrate { bitrate } => {
+                let bitrate = match Bitrate::from_str(&bitrate.to_string()) {
+                    Ok(bitrate) => bitrate,
+                    Err(_) => {
+                        println!("Unsupported bitrate {}, expected 96, 160 or 320", bitrate);
+                        continue;
+                    }
+                };
+
+                let mut player = player.lock().await;
+                if player.bitrate() == bitrate {
+                    continue;
+                }
+
+                let restore = player.spirc.as_ref().and(player.restore_point());
+                player.set_bitrate(bitrate).await;
+                spawn_restore(player.session.clone(), restore);
+            }
+            OperatorMsg::CacheUsage {} => {
+                let settings = player.lock().await.cache_settings.clone();
+                match lib::cache::usage(&settings) {
+                    Ok(usage) => operator.publish_cache_usage(command.reply.clone(), &usage).await,
+                    Err(e) => println!("Could not read the audio cache: {}", e),
+                }
+            }
+            OperatorMsg::PurgeCache {} => {
+                let settings = player.lock().await.cache_settings.clone();
+                match lib::cache::purge(&settings) {
+                    Ok(removed) => {
+                        println!("Purged {} files ({} bytes) from the audio cache", removed.files, removed.bytes);
+                        operator.publish(GrooverEvent::CachePurged { files: removed.files, bytes: removed.bytes }).await;
+                    }
+                    Err(e) => println!("Could not purge the audio cache: {}", e),
+                }
+            }
+            OperatorMsg::Prefetch { uri } => {
+                let (session, bitrate) = {
+                    let player = player.lock().await;
+                    (player.session.clone(), player.bitrate())
+                };
+                let session = match session {
+                    Some(session) => session,
+                    None => {
+                        println!("Not connected to Spotify, can't prefetch {}", uri);
+                        continue;
+                    }
+                };
+
+                let operator = operator.clone();
+                let index = offline_index.clone();
+                tokio::spawn(async move {
+                    let event = match lib::cache::prefetch(&session, &index, &uri, bitrate).await {
+                        Ok(summary) => GrooverEvent::PrefetchFinished { uri, summary: Some(summary), error: None },
+                        Err(e) => GrooverEvent::PrefetchFinished { uri, summary: None, error: Some(e) },
+                    };
+                    operator.publish(event).await;
+                });
+            }
+            OperatorMsg::PlayOffline { uris } => {
+                let mut offline = offline.lock().await;
+                if !offline.active {
+                    println!("Not offline, ignoring PlayOffline");
+                    continue;
+                }
+
+                let player = player.lock().await;
+                let cache = player.cache();
+                let (tracks, unavailable) = offline_index.resolve(cache.as_ref(), &uris);
+                for item in unavailable.iter() {
+                    println!("Can't play {} offline: {}", item.uri, item.reason);
+                }
+
+                let track_uris = tracks.iter().map(|t| t.uri.clone()).collect();
+                if let (Some(cache), false) = (cache, tracks.is_empty()) {
+                    offline.play(player.emitted_sink.clone(), cache, tracks);
+                }
+
+                operator.publish(GrooverEvent::OfflinePlayback { tracks: track_uris, unavailable }).await;
+            }
+            OperatorMsg::Status {} => {
+                let status = {
+                    let driver = driver.lock().await;
+                    let player = player.lock().await;
+
+                    Status {
+                        connected: driver.is_connected(),
+                        source_set: driver.is_source_set,
+                        connect_enabled: player.spirc.is_some(),
+                        recording: recorder.lock().await.is_some(),
+                        offline: offline.lock().await.active,
+                        targets: targets.keys().map(|(g, u)| format!("{}:{}", g, u)).collect(),
+                        sink: player.emitted_sink.metrics(),
+                        subscribers: pipeline.subscriber_metrics(),
+                    }
+                };
+
+                operator.publish_status(command.reply.clone(), &status).await;
+            }
         }
     }
+
+    println!("Shutting down, {} sink underruns", player.lock().await.emitted_sink.metrics().underruns);
+
+    // Ends the songbird input cleanly instead of leaving it waiting on audio
+    player.lock().await.disable_connect().await;
+    offline.lock().await.stop();
+    player.lock().await.emitted_sink.close();
+    if let Some(recorder) = recorder.lock().await.take() {
+        recorder.stop();
+    }
+    pipeline.stop();
+    for (_, mut target) in targets.drain() {
+        target.disconnect().await;
+    }
+    driver.lock().await.disconnect().await;
 }
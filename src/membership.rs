@@ -0,0 +1,92 @@
+use std::collections::{HashMap, HashSet};
+
+/// Who is in which voice channel of the guild, from forwarded voice state updates.
+pub struct Membership {
+    bot_id: u64,
+    channels: HashMap<u64, u64>,
+    bots: HashSet<u64>,
+}
+
+impl Membership {
+    pub fn new(bot_id: u64) -> Membership {
+        Membership {
+            bot_id,
+            channels: HashMap::new(),
+            bots: HashSet::new(),
+        }
+    }
+
+    pub fn update(&mut self, user_id: u64, channel_id: Option<u64>, bot: bool) {
+        match channel_id {
+            Some(channel_id) => self.channels.insert(user_id, channel_id),
+            None => self.channels.remove(&user_id),
+        };
+
+        if bot {
+            self.bots.insert(user_id);
+        } else {
+            self.bots.remove(&user_id);
+        }
+    }
+
+    /// The channel Groover itself is in, as far as the gateway told us.
+    pub fn channel(&self) -> Option<u64> {
+        self.channels.get(&self.bot_id).copied()
+    }
+
+    /// Humans in Groover's channel, `None` while Groover isn't in one.
+    pub fn listeners(&self) -> Option<usize> {
+        let channel = self.channel()?;
+
+        Some(self.channels.iter()
+            .filter(|(user, c)| **c == channel && **user != self.bot_id && !self.bots.contains(user))
+            .count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOT: u64 = 1;
+
+    #[test]
+    fn no_listeners_outside_a_channel() {
+        let mut membership = Membership::new(BOT);
+        membership.update(2, Some(10), false);
+
+        assert_eq!(membership.listeners(), None);
+    }
+
+    #[test]
+    fn counts_humans_in_our_channel() {
+        let mut membership = Membership::new(BOT);
+        membership.update(BOT, Some(10), true);
+        membership.update(2, Some(10), false);
+        membership.update(3, Some(10), false);
+        membership.update(4, Some(11), false);
+        membership.update(5, Some(10), true);
+
+        assert_eq!(membership.channel(), Some(10));
+        assert_eq!(membership.listeners(), Some(2));
+    }
+
+    #[test]
+    fn follows_moves_and_disconnects() {
+        let mut membership = Membership::new(BOT);
+        membership.update(BOT, Some(10), true);
+        membership.update(2, Some(10), false);
+
+        membership.update(2, Some(11), false);
+        assert_eq!(membership.listeners(), Some(0));
+
+        membership.update(BOT, Some(11), true);
+        assert_eq!(membership.listeners(), Some(1));
+
+        membership.update(2, None, false);
+        assert_eq!(membership.listeners(), Some(0));
+
+        membership.update(BOT, None, true);
+        assert_eq!(membership.listeners(), None);
+    }
+}
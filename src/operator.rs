@@ -0,0 +1,436 @@
+use std::env;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use songbird::ConnectionInfo;
+use songbird::id::{GuildId, UserId};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::lib::cache::{CacheUsage, PrefetchSummary};
+use crate::lib::metadata::TrackInfo;
+use crate::lib::offline::Unavailable;
+use crate::lib::pipeline::SubscriberMetrics;
+use crate::lib::recorder::RecordingFormat;
+use crate::lib::sink::SinkMetricsSnapshot;
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "UserId")]
+struct UserIdDef(pub u64);
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "GuildId")]
+pub struct GuildIdDef(pub u64);
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "ConnectionInfo")]
+struct ConnectionInfoDef {
+    endpoint: String,
+    #[serde(with = "GuildIdDef")]
+    guild_id: GuildId,
+    session_id: String,
+    token: String,
+    #[serde(with = "UserIdDef")]
+    user_id: UserId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "value")]
+pub enum OperatorMsg {
+    Join {
+        #[serde(with = "ConnectionInfoDef")]
+        info: ConnectionInfo,
+        /// The voice channel's bitrate in bits per second, picks the Spotify and Opus quality
+        #[serde(default)]
+        bitrate: Option<u32>,
+    },
+    /// Leave the voice channel and stop being a Connect device
+    Leave {
+    },
+    /// A gateway voice state update, Groover follows `FOLLOW_USER_ID` with these
+    VoiceStateUpdate {
+        user_id: u64,
+        /// `None` when the user disconnected
+        channel_id: Option<u64>,
+        /// Bots don't count as listeners
+        #[serde(default)]
+        bot: bool,
+    },
+    PausePlay {
+    },
+    /// Broadcast the player to another call as well
+    Attach {
+        #[serde(with = "ConnectionInfoDef")]
+        info: ConnectionInfo
+    },
+    Detach {
+        guild_id: u64,
+        user_id: u64,
+    },
+    StartRecording {
+        format: RecordingFormat,
+        max_duration_secs: Option<u64>,
+        rotate_secs: Option<u64>,
+    },
+    StopRecording {
+    },
+    Status {
+    },
+    /// Delete the stored Spotify credentials, the next start logs in from scratch
+    ForgetCredentials {
+    },
+    /// Stream at 96, 160 or 320 kbps from now on
+    SetBitrate {
+        bitrate: u16,
+    },
+    /// Report how much of the audio cache is used
+    CacheUsage {
+    },
+    /// Delete every cached audio file
+    PurgeCache {
+    },
+    /// Download every track of a `spotify:playlist:` or `spotify:album:` URI into the cache
+    Prefetch {
+        uri: String,
+    },
+    /// Play track, playlist or album URIs from the cache, only while offline
+    PlayOffline {
+        uris: Vec<String>,
+    },
+    /// Change how the bot shows up in Spotify Connect, unset fields are left alone
+    ConfigureConnect {
+        name: Option<String>,
+        guild_name: Option<String>,
+        device_type: Option<String>,
+        volume: Option<u8>,
+        volume_ctrl: Option<String>,
+        autoplay: Option<bool>,
+    },
+}
+
+/// Events Groover reports back to the operator on `<guild id>.events`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum GrooverEvent {
+    RecordingStarted {
+        format: String,
+        directory: String,
+    },
+    RecordingStopped {
+        files: Vec<String>,
+        error: Option<String>,
+    },
+    TargetAttached {
+        guild_id: u64,
+        user_id: u64,
+    },
+    TargetDetached {
+        guild_id: u64,
+        user_id: u64,
+    },
+    SessionLost {},
+    SessionRestored {
+        attempts: u32,
+        /// Track playback was moved back to, if anything was playing
+        track_uri: Option<String>,
+        position_ms: Option<u32>,
+        error: Option<String>,
+    },
+    CachePurged {
+        files: u64,
+        bytes: u64,
+    },
+    PrefetchFinished {
+        uri: String,
+        summary: Option<PrefetchSummary>,
+        error: Option<String>,
+    },
+    /// Entered when the session can't be restored, left once it is
+    OfflineMode {
+        active: bool,
+    },
+    /// Answer to `PlayOffline`
+    OfflinePlayback {
+        tracks: Vec<String>,
+        unavailable: Vec<Unavailable>,
+    },
+    NowPlaying(TrackInfo),
+    /// The followed user is in `channel_id`, answer with a `Join` for it
+    JoinRequested {
+        channel_id: u64,
+    },
+    /// Groover left the call, the gateway should leave the voice channel too
+    LeaveRequested {},
+    /// The voice connection came up, `rejoin_attempts` is set when it came back after a loss
+    VoiceConnected {
+        rejoin_attempts: Option<u32>,
+    },
+    /// Songbird reconnected on its own, the audio kept going
+    VoiceReconnected {},
+    VoiceConnectFailed {},
+    /// Songbird gave up reconnecting, Groover rejoins with backoff
+    VoiceReconnectFailed {},
+    /// Spotify playback moved from Groover to another Connect device
+    DeviceLost {
+        device_name: String,
+        /// Whether Groover left the voice channel because of it
+        left: bool,
+    },
+    /// Left because playback was paused or stopped for `idle_secs`
+    LeftIdle {
+        idle_secs: u64,
+    },
+    /// Open `url`, log in and send the callback URL or code to `<guild id>.auth`
+    AuthRequired {
+        url: String,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityKind {
+    Playing,
+    Listening,
+    Watching,
+    Competing,
+}
+
+impl FromStr for ActivityKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "playing" => Ok(ActivityKind::Playing),
+            "listening" => Ok(ActivityKind::Listening),
+            "watching" => Ok(ActivityKind::Watching),
+            "competing" => Ok(ActivityKind::Competing),
+            _ => Err(format!("unknown activity type {}", s)),
+        }
+    }
+}
+
+/// Presence the operator should set on the gateway, published on `<guild id>.presence`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum PresenceUpdate {
+    Set {
+        activity: ActivityKind,
+        text: String,
+    },
+    Clear {},
+}
+
+/// How the presence text is built, `PRESENCE_ACTIVITY` and `PRESENCE_TEMPLATE`.
+/// The template can use `{title}`, `{artist}` (the first one), `{artists}` and `{album}`.
+#[derive(Clone, Debug)]
+pub struct PresenceSettings {
+    pub activity: ActivityKind,
+    pub template: String,
+}
+
+impl PresenceSettings {
+    pub fn from_env() -> PresenceSettings {
+        PresenceSettings {
+            activity: match env::var("PRESENCE_ACTIVITY") {
+                Ok(activity) => activity.parse().expect("Invalid PRESENCE_ACTIVITY"),
+                Err(_) => ActivityKind::Listening,
+            },
+            template: env::var("PRESENCE_TEMPLATE").unwrap_or_else(|_| "{artist}: {title}".into()),
+        }
+    }
+
+    pub fn update_for(&self, track: &TrackInfo) -> PresenceUpdate {
+        let text = self.template
+            .replace("{title}", &track.title)
+            .replace("{artists}", &track.artists.join(", "))
+            .replace("{artist}", track.artists.first().map(String::as_str).unwrap_or(""))
+            .replace("{album}", &track.album);
+
+        PresenceUpdate::Set {
+            activity: self.activity,
+            text,
+        }
+    }
+}
+
+/// Reply to a `Status` request.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub connected: bool,
+    pub source_set: bool,
+    pub connect_enabled: bool,
+    pub recording: bool,
+    pub offline: bool,
+    /// Extra calls the player is broadcast to, besides the one from `Join`
+    pub targets: Vec<String>,
+    pub sink: SinkMetricsSnapshot,
+    /// Calls, recordings and stream listeners reading the pipeline
+    pub subscribers: Vec<SubscriberMetrics>,
+}
+
+/// A message from the operator, or from the standalone bot acting as one.
+pub struct Command {
+    pub msg: OperatorMsg,
+    /// Subject to answer on, NATS requests only
+    pub reply: Option<String>,
+}
+
+/// Everything Groover says to and hears from whoever controls it. Without the `operator`
+/// feature or a `NATS_URL` there is nobody listening, events are dropped and commands
+/// only come from within the process.
+#[derive(Clone)]
+pub struct Operator {
+    #[cfg(feature = "operator")]
+    client: Option<async_nats::Client>,
+    guild_id: String,
+    local: UnboundedSender<Command>,
+    local_rx: Arc<Mutex<Option<UnboundedReceiver<Command>>>>,
+    presence: Arc<Mutex<Option<UnboundedSender<PresenceUpdate>>>>,
+    events: Arc<Mutex<Option<UnboundedSender<GrooverEvent>>>>,
+}
+
+impl Operator {
+    /// Connects to `NATS_URL`, which is only optional in the standalone build.
+    pub async fn connect(guild_id: String) -> Operator {
+        #[cfg(feature = "operator")]
+        let client = match env::var("NATS_URL") {
+            Ok(url) => Some(async_nats::connect(url).await.expect("Could not connect to NATS")),
+            Err(_) if cfg!(feature = "standalone") => None,
+            Err(_) => panic!("Expected a NATS URL in the environment"),
+        };
+
+        let (local, local_rx) = unbounded_channel();
+
+        Operator {
+            #[cfg(feature = "operator")]
+            client,
+            guild_id,
+            local,
+            local_rx: Arc::new(Mutex::new(Some(local_rx))),
+            presence: Arc::new(Mutex::new(None)),
+            events: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    #[cfg(feature = "operator")]
+    async fn send(&self, subject: String, payload: Vec<u8>) {
+        if let Some(client) = self.client.as_ref() {
+            if let Err(e) = client.publish(subject.clone(), payload.into()).await {
+                println!("Could not publish to {}: {}", subject, e);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "operator"))]
+    async fn send(&self, _subject: String, _payload: Vec<u8>) {}
+
+    /// Tells the operator this guild's Groover is up, on `ready`.
+    pub async fn announce_ready(&self) {
+        self.send("ready".into(), self.guild_id.clone().into_bytes()).await;
+    }
+
+    /// Commands from `<guild id>` and from `command`, can only be taken once.
+    pub async fn commands(&self) -> Pin<Box<dyn Stream<Item = Command> + Send>> {
+        let local_rx = self.local_rx.lock().unwrap().take().expect("Commands were already taken");
+        let local = futures::stream::unfold(local_rx, |mut rx| async move {
+            rx.recv().await.map(|command| (command, rx))
+        });
+
+        #[cfg(feature = "operator")]
+        if let Some(client) = self.client.as_ref() {
+            let sub = client.subscribe(self.guild_id.clone()).await.unwrap();
+            let remote = sub.filter_map(|msg| async move {
+                match serde_json::from_slice(&msg.payload) {
+                    Ok(cmd) => Some(Command { msg: cmd, reply: msg.reply }),
+                    Err(e) => {
+                        println!("Ignoring invalid operator message: {}", e);
+                        None
+                    }
+                }
+            });
+
+            return Box::pin(futures::stream::select(local, remote));
+        }
+
+        Box::pin(local)
+    }
+
+    /// Handles `msg` as if the operator had sent it.
+    pub fn command(&self, msg: OperatorMsg) {
+        let _ = self.local.send(Command { msg, reply: None });
+    }
+
+    /// Presence updates are also handed to the returned receiver, for the standalone bot
+    /// that owns a gateway connection itself.
+    pub fn watch_presence(&self) -> UnboundedReceiver<PresenceUpdate> {
+        let (sender, receiver) = unbounded_channel();
+        *self.presence.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Events are also handed to the returned receiver.
+    pub fn watch_events(&self) -> UnboundedReceiver<GrooverEvent> {
+        let (sender, receiver) = unbounded_channel();
+        *self.events.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    pub async fn publish(&self, event: GrooverEvent) {
+        if let Some(sender) = self.events.lock().unwrap().as_ref() {
+            let _ = sender.send(event.clone());
+        }
+
+        let payload = serde_json::to_vec(&event).unwrap();
+        self.send(format!("{}.events", self.guild_id), payload).await;
+    }
+
+    pub async fn publish_presence(&self, update: PresenceUpdate) {
+        if let Some(sender) = self.presence.lock().unwrap().as_ref() {
+            let _ = sender.send(update.clone());
+        }
+
+        let payload = serde_json::to_vec(&update).unwrap();
+        self.send(format!("{}.presence", self.guild_id), payload).await;
+    }
+
+    /// Publishes the Spotify authorize URL and waits for the operator to answer with
+    /// the OAuth callback URL or code on `<guild id>.auth`.
+    #[cfg(feature = "operator")]
+    pub async fn request_auth(&self, url: String) -> String {
+        let client = self.client.as_ref().expect("OAUTH_MODE=operator needs NATS_URL");
+        let mut sub = client.subscribe(format!("{}.auth", self.guild_id)).await.unwrap();
+
+        self.publish(GrooverEvent::AuthRequired { url }).await;
+
+        loop {
+            let msg = sub.next().await.expect("Lost the NATS subscription while logging in");
+            let reply = String::from_utf8_lossy(&msg.payload).trim().to_string();
+
+            if !reply.is_empty() {
+                return reply;
+            }
+        }
+    }
+
+    #[cfg(not(feature = "operator"))]
+    pub async fn request_auth(&self, _url: String) -> String {
+        panic!("OAUTH_MODE=operator needs the operator feature");
+    }
+
+    /// Answers on the request's reply subject, or `<guild id>.status` if there is none.
+    pub async fn publish_status(&self, reply: Option<String>, status: &Status) {
+        self.answer(reply, "status", status).await;
+    }
+
+    /// Answers on the request's reply subject, or `<guild id>.cache` if there is none.
+    pub async fn publish_cache_usage(&self, reply: Option<String>, usage: &CacheUsage) {
+        self.answer(reply, "cache", usage).await;
+    }
+
+    async fn answer<T: Serialize>(&self, reply: Option<String>, fallback: &str, body: &T) {
+        let subject = reply.unwrap_or_else(|| format!("{}.{}", self.guild_id, fallback));
+        self.send(subject, serde_json::to_vec(body).unwrap()).await;
+    }
+}
@@ -0,0 +1,159 @@
+use std::env;
+
+use serenity::{
+    async_trait,
+    client::{bridge::gateway::GatewayIntents, Client, Context, EventHandler},
+    model::{gateway, gateway::Ready, id, user, voice::VoiceState},
+};
+use songbird::SerenityInit;
+
+use crate::operator::{ActivityKind, GrooverEvent, Operator, OperatorMsg, PresenceUpdate};
+
+/// Runs Groover as its own Discord bot, for when there is no operator. It does the
+/// operator's part itself: forwarding voice states, answering join and leave requests,
+/// and setting presence.
+struct Handler {
+    operator: Operator,
+    guild_id: id::GuildId,
+}
+
+impl Handler {
+    fn forward(&self, voice_state: &VoiceState) {
+        self.operator.command(OperatorMsg::VoiceStateUpdate {
+            user_id: voice_state.user_id.0,
+            channel_id: voice_state.channel_id.map(|c| c.0),
+            bot: voice_state.member.as_ref().map_or(false, |m| m.user.bot),
+        });
+    }
+}
+
+/// Joins `channel_id` through the gateway and has the driver connect to it.
+async fn join(ctx: &Context, operator: &Operator, guild_id: id::GuildId, channel_id: id::ChannelId) {
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialisation.")
+        .clone();
+
+    let (_, result) = manager.join_gateway(guild_id, channel_id).await;
+    let info = match result {
+        Ok(receiver) => match receiver.recv_async().await {
+            Ok(info) => info,
+            Err(e) => {
+                println!("Could not join voice channel: {:?}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            println!("Could not join voice channel: {:?}", e);
+            return;
+        }
+    };
+
+    let bitrate = ctx.cache
+        .guild_channel(channel_id)
+        .await
+        .and_then(|channel| channel.bitrate)
+        .map(|bitrate| bitrate as u32);
+
+    operator.command(OperatorMsg::Join { info, bitrate });
+}
+
+async fn leave(ctx: &Context, guild_id: id::GuildId) {
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialisation.")
+        .clone();
+
+    let _ = manager.remove(guild_id).await;
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        println!("Ready!");
+        println!("Invite me with https://discord.com/api/oauth2/authorize?client_id={}&permissions=36700160&scope=bot", ready.user.id);
+
+        ctx.set_presence(None, user::OnlineStatus::Online).await;
+
+        // We own the gateway, so presence requests end up here instead of with an operator
+        let mut presence = self.operator.watch_presence();
+        let c = ctx.clone();
+        tokio::spawn(async move {
+            while let Some(update) = presence.recv().await {
+                let activity = match update {
+                    PresenceUpdate::Set { activity, text } => Some(match activity {
+                        ActivityKind::Playing => gateway::Activity::playing(text),
+                        ActivityKind::Listening => gateway::Activity::listening(text),
+                        ActivityKind::Watching => gateway::Activity::watching(text),
+                        ActivityKind::Competing => gateway::Activity::competing(text),
+                    }),
+                    PresenceUpdate::Clear {} => None,
+                };
+
+                c.set_presence(activity, user::OnlineStatus::Online).await;
+            }
+        });
+
+        let mut events = self.operator.watch_events();
+        let operator = self.operator.clone();
+        let guild_id = self.guild_id;
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    GrooverEvent::JoinRequested { channel_id } => {
+                        join(&ctx, &operator, guild_id, id::ChannelId(channel_id)).await;
+                    }
+                    GrooverEvent::LeaveRequested {} => leave(&ctx, guild_id).await,
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    async fn cache_ready(&self, ctx: Context, _: Vec<id::GuildId>) {
+        // Handle case when users are in VC when bot starts
+        let guild = ctx
+            .cache
+            .guild(self.guild_id)
+            .await
+            .expect("Could not find guild in cache.");
+
+        for voice_state in guild.voice_states.values() {
+            self.forward(voice_state);
+        }
+    }
+
+    async fn voice_state_update(
+        &self,
+        _: Context,
+        guild_id: Option<id::GuildId>,
+        _: Option<VoiceState>,
+        new: VoiceState,
+    ) {
+        if guild_id.map_or(false, |g| g != self.guild_id) {
+            return;
+        }
+
+        self.forward(&new);
+    }
+}
+
+pub async fn run(operator: Operator, guild_id: String) {
+    let token = env::var("DISCORD_TOKEN").expect("Expected a Discord bot token in the environment");
+
+    let handler = Handler {
+        operator,
+        guild_id: id::GuildId(guild_id.parse().expect("Invalid DISCORD_GUILD_ID")),
+    };
+
+    let mut client = Client::builder(&token)
+        .intents(GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES)
+        .event_handler(handler)
+        .register_songbird()
+        .await
+        .expect("Error creating Discord client");
+
+    if let Err(e) = client.start().await {
+        println!("Discord client ended: {:?}", e);
+    }
+}